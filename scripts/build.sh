@@ -12,12 +12,14 @@ if [ "$MODE" = "release" ]; then
     echo "🚀 Building in release mode..."
     cargo build --target $TARGET --release
     if [ "$TARGET" = "x86_64-kewve_os.json" ]; then
+        ./scripts/embed-symbols.sh target/x86_64-kewve_os/release/kewve-os
         cargo bootimage --target $TARGET --release
     fi
 else
     echo "🛠️  Building in debug mode..."
     cargo build --target $TARGET
     if [ "$TARGET" = "x86_64-kewve_os.json" ]; then
+        ./scripts/embed-symbols.sh target/x86_64-kewve_os/debug/kewve-os
         cargo bootimage --target $TARGET
     fi
 fi
@@ -0,0 +1,46 @@
+#!/bin/bash
+# Embed the symbol table used by kernel backtraces into a linked kernel
+#
+# Usage: scripts/embed-symbols.sh <kernel ELF>
+
+set -e
+
+KERNEL=${1:?"Usage: $0 <kernel ELF>"}
+SECTION=.kernel_symbols
+
+SIZE=$(objdump -h "$KERNEL" | awk -v section="$SECTION" '$2 == section { print $3 }')
+if [ -z "$SIZE" ]; then
+    echo "❌ $KERNEL has no $SECTION section"
+    exit 1
+fi
+SIZE=$((16#$SIZE))
+
+TABLE=$(mktemp)
+trap 'rm -f "$TABLE"' EXIT
+
+# One "<address> <size> <name>" line per function; nm leaves out the size
+# of symbols that have none
+{
+    echo "KSYM"
+    nm --defined-only --numeric-sort --print-size --demangle "$KERNEL" | awk '
+        NF >= 4 && $3 ~ /^[tTwW]$/ { size = $2; first = 4 }
+        NF >= 3 && $2 ~ /^[tTwW]$/ { size = 0; first = 3 }
+        first {
+            name = $first
+            for (i = first + 1; i <= NF; i++) name = name " " $i
+            print $1, size, name
+            first = 0
+        }'
+} > "$TABLE"
+
+USED=$(stat -c %s "$TABLE")
+if [ "$USED" -ge "$SIZE" ]; then
+    echo "❌ The symbol table needs $USED bytes but only $SIZE are reserved"
+    echo "   Raise SYMBOL_TABLE_SIZE in src/backtrace/symbols.rs"
+    exit 1
+fi
+
+# The section must keep its size, and the zero padding ends the table
+truncate -s "$SIZE" "$TABLE"
+objcopy --update-section "$SECTION=$TABLE" "$KERNEL"
+echo "🔎 Embedded $(($(wc -l < "$TABLE") - 1)) symbols into $KERNEL"
@@ -0,0 +1,200 @@
+//! Fixed ACPI Description Table
+//!
+//! Describes the fixed power management hardware: the PM1 event and
+//! control blocks, the PM timer, the SCI interrupt and the reset register.
+
+use super::{find_table, read, AcpiError, Signature};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+/// Signature of the FADT
+pub const SIGNATURE: Signature = *b"FACP";
+
+/// IAPC_BOOT_ARCH: the machine has ISA devices such as the PIT and RTC
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+/// IAPC_BOOT_ARCH: the machine has an 8042 keyboard controller
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// Offset of the ACPI 2.0 reset register and extended address fields
+const RESET_REGISTER_OFFSET: u64 = 116;
+const X_PM1A_EVENT_BLOCK_OFFSET: u64 = 148;
+
+/// Address space of a generic address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// An ACPI generic address structure
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub(super) fn read(addr: PhysAddr) -> Self {
+        unsafe {
+            GenericAddress {
+                space: match read::<u8>(addr) {
+                    0 => AddressSpace::SystemMemory,
+                    1 => AddressSpace::SystemIo,
+                    2 => AddressSpace::PciConfig,
+                    other => AddressSpace::Other(other),
+                },
+                bit_width: read(addr + 1u64),
+                bit_offset: read(addr + 2u64),
+                access_size: read(addr + 3u64),
+                address: read(addr + 4u64),
+            }
+        }
+    }
+
+    /// An I/O port block from a legacy 32-bit FADT field
+    fn io_port(port: u32, len: u8) -> Option<Self> {
+        (port != 0).then_some(GenericAddress {
+            space: AddressSpace::SystemIo,
+            bit_width: len * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        })
+    }
+}
+
+/// Parsed FADT
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysAddr,
+    /// Legacy IRQ the system control interrupt is wired to
+    pub sci_interrupt: u16,
+    /// Port written with `acpi_enable` to hand power management to the OS
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: Option<GenericAddress>,
+    pub pm1b_event_block: Option<GenericAddress>,
+    pub pm1a_control_block: Option<GenericAddress>,
+    pub pm1b_control_block: Option<GenericAddress>,
+    pub pm_timer_block: Option<GenericAddress>,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    /// CMOS RTC index of the century, or zero
+    pub century: u8,
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Find and parse the FADT
+    pub fn parse() -> Result<Self, AcpiError> {
+        let (addr, header) = find_table(&SIGNATURE)?;
+        let length = header.length as u64;
+        // Every field up to and including the flags is present since ACPI 1.0
+        if length < 116 {
+            return Err(AcpiError::MalformedTable(SIGNATURE));
+        }
+
+        let field = |offset: u64| addr + offset;
+        let mut fadt = unsafe {
+            let pm1_event_length: u8 = read(field(88));
+            let pm1_control_length: u8 = read(field(89));
+            Fadt {
+                revision: header.revision,
+                dsdt: PhysAddr::new(read::<u32>(field(40)) as u64),
+                sci_interrupt: read(field(46)),
+                smi_command_port: read(field(48)),
+                acpi_enable: read(field(52)),
+                acpi_disable: read(field(53)),
+                pm1a_event_block: GenericAddress::io_port(read(field(56)), pm1_event_length),
+                pm1b_event_block: GenericAddress::io_port(read(field(60)), pm1_event_length),
+                pm1a_control_block: GenericAddress::io_port(read(field(64)), pm1_control_length),
+                pm1b_control_block: GenericAddress::io_port(read(field(68)), pm1_control_length),
+                pm_timer_block: GenericAddress::io_port(read(field(76)), read(field(91))),
+                pm1_event_length,
+                pm1_control_length,
+                century: read(field(108)),
+                boot_architecture: read(field(109)),
+                flags: read(field(112)),
+                reset_register: None,
+                reset_value: 0,
+            }
+        };
+
+        if length >= RESET_REGISTER_OFFSET + 13 {
+            fadt.reset_register = Some(GenericAddress::read(field(RESET_REGISTER_OFFSET)));
+            fadt.reset_value = unsafe { read(field(RESET_REGISTER_OFFSET + 12)) };
+        }
+        // ACPI 2.0 64-bit fields take precedence over the legacy ones when set
+        if length >= X_PM1A_EVENT_BLOCK_OFFSET + 6 * 12 {
+            let x_dsdt: u64 = unsafe { read(field(140)) };
+            if x_dsdt != 0 {
+                fadt.dsdt = PhysAddr::new(x_dsdt);
+            }
+            let blocks = [
+                &mut fadt.pm1a_event_block,
+                &mut fadt.pm1b_event_block,
+                &mut fadt.pm1a_control_block,
+                &mut fadt.pm1b_control_block,
+            ];
+            for (index, block) in blocks.into_iter().enumerate() {
+                let extended = GenericAddress::read(field(X_PM1A_EVENT_BLOCK_OFFSET + index as u64 * 12));
+                if extended.address != 0 {
+                    *block = Some(extended);
+                }
+            }
+            // X_PM_TMR_BLK follows X_PM2_CNT_BLK
+            let timer = GenericAddress::read(field(X_PM1A_EVENT_BLOCK_OFFSET + 5 * 12));
+            if timer.address != 0 {
+                fadt.pm_timer_block = Some(timer);
+            }
+        }
+        Ok(fadt)
+    }
+
+    /// Whether the machine has legacy ISA devices
+    ///
+    /// Only meaningful from ACPI 2.0 on; older firmware always has them.
+    pub fn has_legacy_devices(&self) -> bool {
+        self.revision < 2 || self.boot_architecture & BOOT_ARCH_LEGACY_DEVICES != 0
+    }
+
+    /// Whether the machine has an 8042 PS/2 controller
+    pub fn has_8042(&self) -> bool {
+        self.revision < 2 || self.boot_architecture & BOOT_ARCH_8042 != 0
+    }
+
+    /// Read the PM1 control registers, combining the A and B blocks
+    pub fn read_pm1_control(&self) -> Option<u16> {
+        let a = read_io_block(self.pm1a_control_block?)?;
+        let b = self.pm1b_control_block.and_then(read_io_block).unwrap_or(0);
+        Some(a | b)
+    }
+
+    /// Write `value` to both PM1 control blocks
+    pub fn write_pm1_control(&self, value: u16) -> Option<()> {
+        write_io_block(self.pm1a_control_block?, value)?;
+        if let Some(block) = self.pm1b_control_block {
+            write_io_block(block, value)?;
+        }
+        Some(())
+    }
+}
+
+/// Read a 16-bit register in I/O space; other spaces are not supported
+fn read_io_block(block: GenericAddress) -> Option<u16> {
+    (block.space == AddressSpace::SystemIo).then(|| unsafe { Port::<u16>::new(block.address as u16).read() })
+}
+
+fn write_io_block(block: GenericAddress, value: u16) -> Option<()> {
+    (block.space == AddressSpace::SystemIo).then(|| unsafe { Port::<u16>::new(block.address as u16).write(value) })
+}
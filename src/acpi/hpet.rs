@@ -0,0 +1,53 @@
+//! High Precision Event Timer description table
+
+use super::fadt::{AddressSpace, GenericAddress};
+use super::{find_table, read, AcpiError, SdtHeader, Signature};
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+/// Signature of the HPET table
+pub const SIGNATURE: Signature = *b"HPET";
+
+/// Parsed HPET table
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    /// Number of comparators (timers) in the block
+    pub comparator_count: u8,
+    pub counter_is_64_bit: bool,
+    /// The block can replace the PIT and RTC interrupts
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// Physical address of the register block
+    pub base_address: PhysAddr,
+    pub number: u8,
+    /// Minimum clock ticks for periodic mode without losing interrupts
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Find and parse the HPET table
+    pub fn parse() -> Result<Self, AcpiError> {
+        let (addr, header) = find_table(&SIGNATURE)?;
+        let body = addr + size_of::<SdtHeader>() as u64;
+        if (header.length as usize) < size_of::<SdtHeader>() + 20 {
+            return Err(AcpiError::MalformedTable(SIGNATURE));
+        }
+
+        let id: u32 = unsafe { read(body) };
+        let base = GenericAddress::read(body + 4u64);
+        if base.space != AddressSpace::SystemMemory {
+            return Err(AcpiError::MalformedTable(SIGNATURE));
+        }
+        Ok(Hpet {
+            hardware_revision: id as u8,
+            comparator_count: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_is_64_bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            base_address: PhysAddr::new(base.address),
+            number: unsafe { read(body + 16u64) },
+            minimum_tick: unsafe { read(body + 17u64) },
+        })
+    }
+}
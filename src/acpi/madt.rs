@@ -0,0 +1,222 @@
+//! Multiple APIC Description Table
+//!
+//! Lists the processors' local APICs, the I/O APICs, and how legacy ISA IRQs
+//! are wired to global system interrupts (GSIs).
+
+use super::{find_table, read, AcpiError, SdtHeader, Signature};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+/// Signature of the MADT
+pub const SIGNATURE: Signature = *b"APIC";
+
+/// Polarity of an interrupt line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// A processor and its local APIC
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// Usable now; disabled processors may still be brought online if `online_capable`
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+/// An I/O APIC and the first GSI it serves
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// A legacy IRQ that is not identity-mapped to a GSI or not edge/active-high
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A local APIC LINT pin wired to NMI
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// Processor UID, or `None` for all processors
+    pub processor_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Parsed MADT
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The machine also has 8259 PICs, which must be masked when the APIC is used
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    /// Find and parse the MADT
+    pub fn parse() -> Result<Self, AcpiError> {
+        let (addr, header) = find_table(&SIGNATURE)?;
+        // Local APIC address and flags follow the header
+        if (header.length as usize) < size_of::<SdtHeader>() + 8 {
+            return Err(AcpiError::MalformedTable(SIGNATURE));
+        }
+        let fixed = addr + size_of::<SdtHeader>() as u64;
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(unsafe { read::<u32>(fixed) } as u64),
+            has_legacy_pics: unsafe { read::<u32>(fixed + 4u64) } & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let end = addr + header.length as u64;
+        let mut entry = fixed + 8u64;
+        while entry + 2u64 <= end {
+            let kind: u8 = unsafe { read(entry) };
+            let len: u8 = unsafe { read(entry + 1u64) };
+            if len < 2 || entry + len as u64 > end {
+                return Err(AcpiError::MalformedTable(SIGNATURE));
+            }
+            madt.parse_entry(kind, len, entry + 2u64)?;
+            entry += len as u64;
+        }
+        Ok(madt)
+    }
+
+    /// Parse the body of one interrupt controller structure
+    fn parse_entry(&mut self, kind: u8, len: u8, body: PhysAddr) -> Result<(), AcpiError> {
+        let require = |size: u8| {
+            if len < size {
+                Err(AcpiError::MalformedTable(SIGNATURE))
+            } else {
+                Ok(())
+            }
+        };
+        unsafe {
+            match kind {
+                // Processor local APIC
+                0 => {
+                    require(8)?;
+                    let flags: u32 = read(body + 2u64);
+                    self.processors.push(Processor {
+                        processor_uid: read::<u8>(body) as u32,
+                        apic_id: read::<u8>(body + 1u64) as u32,
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0,
+                    });
+                }
+                // I/O APIC
+                1 => {
+                    require(12)?;
+                    self.io_apics.push(IoApicEntry {
+                        id: read(body),
+                        address: PhysAddr::new(read::<u32>(body + 2u64) as u64),
+                        gsi_base: read(body + 6u64),
+                    });
+                }
+                // Interrupt source override
+                2 => {
+                    require(10)?;
+                    let (polarity, trigger) = decode_flags(read(body + 6u64));
+                    self.overrides.push(InterruptOverride {
+                        bus: read(body),
+                        source_irq: read(body + 1u64),
+                        gsi: read(body + 2u64),
+                        polarity,
+                        trigger,
+                    });
+                }
+                // Local APIC NMI
+                4 => {
+                    require(6)?;
+                    let uid: u8 = read(body);
+                    let (polarity, trigger) = decode_flags(read(body + 1u64));
+                    self.local_apic_nmis.push(LocalApicNmi {
+                        processor_uid: (uid != 0xff).then_some(uid as u32),
+                        lint: read(body + 3u64),
+                        polarity,
+                        trigger,
+                    });
+                }
+                // Local APIC address override
+                5 => {
+                    require(12)?;
+                    self.local_apic_address = PhysAddr::new(read(body + 2u64));
+                }
+                // Processor local x2APIC
+                9 => {
+                    require(16)?;
+                    let flags: u32 = read(body + 6u64);
+                    self.processors.push(Processor {
+                        processor_uid: read(body + 10u64),
+                        apic_id: read(body + 2u64),
+                        enabled: flags & 1 != 0,
+                        online_capable: flags & 2 != 0,
+                    });
+                }
+                // Local x2APIC NMI
+                10 => {
+                    require(12)?;
+                    let uid: u32 = read(body + 2u64);
+                    let (polarity, trigger) = decode_flags(read(body));
+                    self.local_apic_nmis.push(LocalApicNmi {
+                        processor_uid: (uid != u32::MAX).then_some(uid),
+                        lint: read(body + 6u64),
+                        polarity,
+                        trigger,
+                    });
+                }
+                // Structures this kernel does not use
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// GSI, polarity and trigger mode of legacy ISA `irq`
+    pub fn isa_irq_route(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        self.overrides
+            .iter()
+            .find(|entry| entry.bus == 0 && entry.source_irq == irq)
+            .map_or((irq as u32, Polarity::ActiveHigh, TriggerMode::Edge), |entry| {
+                (entry.gsi, entry.polarity, entry.trigger)
+            })
+    }
+}
+
+/// Decode MPS INTI flags; "conforms to the bus" means ISA defaults, active high and edge
+fn decode_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    };
+    (polarity, trigger)
+}
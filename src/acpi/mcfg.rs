@@ -0,0 +1,55 @@
+//! PCI Express memory-mapped configuration space table
+
+use super::{find_table, read, AcpiError, SdtHeader, Signature};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+/// Signature of the MCFG
+pub const SIGNATURE: Signature = *b"MCFG";
+
+/// Size of each configuration space allocation entry
+const ENTRY_SIZE: usize = 16;
+
+/// Memory-mapped configuration space of one PCI segment's bus range
+#[derive(Debug, Clone, Copy)]
+pub struct PciConfigRegion {
+    pub base_address: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl PciConfigRegion {
+    /// Physical address of the 4 KiB configuration space of a function
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = ((bus - self.start_bus) as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        Some(self.base_address + offset)
+    }
+}
+
+/// Find the MCFG and return its configuration space regions
+pub fn parse() -> Result<Vec<PciConfigRegion>, AcpiError> {
+    let (addr, header) = find_table(&SIGNATURE)?;
+    // Eight reserved bytes follow the header
+    let first = size_of::<SdtHeader>() + 8;
+    let count = (header.length as usize).saturating_sub(first) / ENTRY_SIZE;
+
+    let regions = (0..count)
+        .map(|index| {
+            let entry = addr + (first + index * ENTRY_SIZE) as u64;
+            unsafe {
+                PciConfigRegion {
+                    base_address: PhysAddr::new(read(entry)),
+                    segment: read(entry + 8u64),
+                    start_bus: read(entry + 10u64),
+                    end_bus: read(entry + 11u64),
+                }
+            }
+        })
+        .collect();
+    Ok(regions)
+}
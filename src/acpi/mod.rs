@@ -0,0 +1,247 @@
+//! ACPI table discovery for KewveOS
+//!
+//! Finds the RSDP in the BIOS areas, validates it, and walks the RSDT or
+//! XSDT to locate the system description tables. Tables are read in place
+//! through the kernel's physical memory mapping, and every table's checksum
+//! is checked before it is used.
+//!
+//! `init` parses the tables the kernel uses once at boot; platform,
+//! interrupt and driver code read them through `tables`.
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+pub use mcfg::PciConfigRegion;
+
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// Four-character identifier of a system description table
+pub type Signature = [u8; 4];
+
+/// ACPI errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP in the BIOS areas
+    RsdpNotFound,
+    /// A table's bytes do not sum to zero
+    InvalidChecksum(Signature),
+    /// The root table does not list a table with this signature
+    TableNotFound(Signature),
+    /// A table is shorter than its fixed fields or has a malformed entry
+    MalformedTable(Signature),
+}
+
+impl core::fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            AcpiError::RsdpNotFound => write!(f, "ACPI RSDP not found"),
+            AcpiError::InvalidChecksum(signature) => {
+                write!(f, "Invalid checksum in ACPI table {}", SignatureDisplay(signature))
+            }
+            AcpiError::TableNotFound(signature) => write!(f, "ACPI table {} not found", SignatureDisplay(signature)),
+            AcpiError::MalformedTable(signature) => write!(f, "Malformed ACPI table {}", SignatureDisplay(signature)),
+        }
+    }
+}
+
+struct SignatureDisplay<'a>(&'a Signature);
+
+impl core::fmt::Display for SignatureDisplay<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for &byte in self.0 {
+            let c = if byte.is_ascii_graphic() { byte as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// The tables parsed at boot; tables the firmware does not provide are absent
+#[derive(Debug, Clone)]
+pub struct AcpiTables {
+    /// ACPI revision from the RSDP; 0 for ACPI 1.0, 2 or above otherwise
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub pci_config_regions: Vec<PciConfigRegion>,
+}
+
+impl AcpiTables {
+    /// Number of processors that are enabled or can be brought online
+    pub fn processor_count(&self) -> usize {
+        self.madt.as_ref().map_or(1, |madt| {
+            madt.processors
+                .iter()
+                .filter(|processor| processor.enabled || processor.online_capable)
+                .count()
+                .max(1)
+        })
+    }
+}
+
+static TABLES: Once<AcpiTables> = Once::new();
+
+/// Find and parse the ACPI tables
+///
+/// Optional tables that are missing or fail validation are reported over
+/// serial and left out. Only a missing or corrupt RSDP is an error. Calling
+/// this again returns the tables parsed the first time.
+pub fn init() -> Result<&'static AcpiTables, AcpiError> {
+    if let Some(tables) = TABLES.get() {
+        return Ok(tables);
+    }
+    let rsdp = find_rsdp()?;
+    let tables = AcpiTables {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        madt: optional(madt::SIGNATURE, Madt::parse()),
+        fadt: optional(fadt::SIGNATURE, Fadt::parse()),
+        hpet: optional(hpet::SIGNATURE, Hpet::parse()),
+        pci_config_regions: optional(mcfg::SIGNATURE, mcfg::parse()).unwrap_or_default(),
+    };
+    Ok(TABLES.call_once(|| tables))
+}
+
+/// The tables parsed by `init`, if it has run successfully
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.get()
+}
+
+fn optional<T>(signature: Signature, table: Result<T, AcpiError>) -> Option<T> {
+    match table {
+        Ok(table) => Some(table),
+        Err(AcpiError::TableNotFound(_)) => None,
+        Err(error) => {
+            crate::serial_println!("ACPI: ignoring {}: {}", SignatureDisplay(&signature), error);
+            None
+        }
+    }
+}
+
+/// Root System Description Pointer, including the ACPI 2.0 fields
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP covered by `checksum`
+const RSDP_V1_SIZE: usize = 20;
+
+/// Header shared by every system description table
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: Signature,
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Physical address and header of the table with `signature`, checksum validated
+pub fn find_table(signature: &Signature) -> Result<(PhysAddr, SdtHeader), AcpiError> {
+    let (root, entry_size) = root_table()?;
+    let header = read_header(root)?;
+    let entries = (header.length as usize).saturating_sub(size_of::<SdtHeader>()) / entry_size;
+
+    for index in 0..entries {
+        let entry = root + (size_of::<SdtHeader>() + index * entry_size) as u64;
+        let address = if entry_size == 8 {
+            unsafe { read::<u64>(entry) }
+        } else {
+            unsafe { read::<u32>(entry) as u64 }
+        };
+        // A corrupt entry must not bring down the boot
+        let address = PhysAddr::try_new(address).map_err(|_| AcpiError::MalformedTable(header.signature))?;
+        let candidate: SdtHeader = unsafe { read(address) };
+        if candidate.signature == *signature {
+            return read_header(address).map(|header| (address, header));
+        }
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+/// The XSDT if the firmware provides one, otherwise the RSDT, with the size of its entries
+fn root_table() -> Result<(PhysAddr, usize), AcpiError> {
+    let rsdp = find_rsdp()?;
+    if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let xsdt = PhysAddr::try_new(rsdp.xsdt_address).map_err(|_| AcpiError::MalformedTable(*b"XSDT"))?;
+        Ok((xsdt, 8))
+    } else {
+        Ok((PhysAddr::new(rsdp.rsdt_address as u64), 4))
+    }
+}
+
+/// Search the first KiB of the EBDA, then the BIOS ROM area, for a valid RSDP
+fn find_rsdp() -> Result<Rsdp, AcpiError> {
+    let ebda = (unsafe { read::<u16>(PhysAddr::new(0x40e)) } as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        // The RSDP is always 16-byte aligned
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            let signature: [u8; 8] = unsafe { read(addr) };
+            if &signature != b"RSD PTR " || !checksum_valid(addr, RSDP_V1_SIZE) {
+                continue;
+            }
+            let rsdp: Rsdp = unsafe { read(addr) };
+            if rsdp.revision >= 2 && !checksum_valid(addr, rsdp.length as usize) {
+                continue;
+            }
+            return Ok(rsdp);
+        }
+    }
+    Err(AcpiError::RsdpNotFound)
+}
+
+fn read_header(addr: PhysAddr) -> Result<SdtHeader, AcpiError> {
+    let header: SdtHeader = unsafe { read(addr) };
+    if (header.length as usize) < size_of::<SdtHeader>() {
+        return Err(AcpiError::MalformedTable(header.signature));
+    }
+    if !checksum_valid(addr, header.length as usize) {
+        return Err(AcpiError::InvalidChecksum(header.signature));
+    }
+    Ok(header)
+}
+
+/// Whether the `len` bytes at `addr` sum to zero
+fn checksum_valid(addr: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Read a possibly unaligned value from physical memory
+///
+/// # Safety
+/// `addr` must be covered by the physical memory mapping.
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    core::ptr::read_unaligned(phys_to_virt(addr).as_ptr::<T>())
+}
@@ -0,0 +1,164 @@
+//! Stack traces for panics and exceptions
+//!
+//! The kernel is built with frame pointers, so every frame starts with the
+//! caller's frame pointer followed by the return address, and following
+//! that chain recovers the call stack without unwind tables. Every frame is
+//! checked against the active page tables before it is read, so a corrupt
+//! chain ends the trace instead of faulting again.
+//!
+//! Addresses are resolved to function names through the symbol table in
+//! `symbols`.
+
+pub mod symbols;
+
+use crate::memory::paging::is_mapped;
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Deepest call chain a backtrace records
+pub const MAX_FRAMES: usize = 16;
+
+/// Set while a backtrace is being captured
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// Frame pointer of the calling function
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let frame: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+    frame
+}
+
+/// Return addresses found by following the frame pointer chain from `frame`
+pub fn return_addresses(frame: usize) -> ReturnAddresses {
+    ReturnAddresses { frame }
+}
+
+/// Iterator over the return addresses of a frame pointer chain
+pub struct ReturnAddresses {
+    frame: usize,
+}
+
+impl Iterator for ReturnAddresses {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let frame = self.frame;
+        if !is_readable(frame, 2) {
+            return None;
+        }
+        let (next, return_address) = unsafe { (*(frame as *const usize), *(frame as *const usize).add(1)) };
+        // Stacks grow down, so every caller's frame lies above its callee's
+        self.frame = if next > frame { next } else { 0 };
+        (return_address != 0).then_some(return_address)
+    }
+}
+
+/// Whether the `slots` words at `frame` can be read without faulting
+fn is_readable(frame: usize, slots: usize) -> bool {
+    if frame == 0 || frame & 0x7 != 0 {
+        return false;
+    }
+    let last = frame + (slots - 1) * 8;
+    [frame, last]
+        .into_iter()
+        .all(|addr| VirtAddr::try_new(addr as u64).is_ok_and(is_mapped))
+}
+
+/// A captured call stack
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    addresses: [usize; MAX_FRAMES],
+    len: usize,
+    /// The first address is where an exception struck rather than a return address
+    exact_first: bool,
+}
+
+impl Backtrace {
+    /// Capture the call stack of the caller
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::walk(None, frame_pointer())
+    }
+
+    /// Capture the call stack of the code `stack_frame` interrupted
+    ///
+    /// Must be called while the exception handler is still on the stack.
+    /// The handler's frame lies just below the frame the CPU pushed, with an
+    /// error code in between for some exceptions, and the frame pointer it
+    /// saved is that of the interrupted code.
+    pub fn interrupted(stack_frame: &InterruptStackFrame) -> Self {
+        let rip = stack_frame.instruction_pointer.as_u64() as usize;
+        let cs = stack_frame.code_segment as usize;
+        let mut frame = frame_pointer();
+        for _ in 0..MAX_FRAMES {
+            if !is_readable(frame, 4) {
+                break;
+            }
+            let slots = frame as *const usize;
+            let (saved, first, second, third) = unsafe { (*slots, *slots.add(1), *slots.add(2), *slots.add(3)) };
+            if (first == rip && second == cs) || (second == rip && third == cs) {
+                return Self::walk(Some(rip), saved);
+            }
+            frame = saved;
+        }
+        Self::walk(Some(rip), 0)
+    }
+
+    fn walk(first: Option<usize>, frame: usize) -> Self {
+        let mut backtrace = Backtrace {
+            addresses: [0; MAX_FRAMES],
+            len: 0,
+            exact_first: first.is_some(),
+        };
+        if let Some(address) = first {
+            backtrace.push(address);
+        }
+        // A fault while walking would otherwise report, and walk, again
+        if CAPTURING.swap(true, Ordering::Acquire) {
+            return backtrace;
+        }
+        for address in return_addresses(frame) {
+            if backtrace.len == MAX_FRAMES {
+                break;
+            }
+            backtrace.push(address);
+        }
+        CAPTURING.store(false, Ordering::Release);
+        backtrace
+    }
+
+    fn push(&mut self, address: usize) {
+        self.addresses[self.len] = address;
+        self.len += 1;
+    }
+
+    /// The recorded addresses, innermost first
+    pub fn addresses(&self) -> &[usize] {
+        &self.addresses[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+        if self.len == 0 {
+            return write!(f, " <unavailable>");
+        }
+        for (index, &address) in self.addresses().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x} ", index, address)?;
+            // A return address follows the call, which may be the last
+            // instruction of the calling function
+            let exact = index == 0 && self.exact_first;
+            let lookup_address = if exact { address } else { address - 1 };
+            match symbols::lookup(lookup_address) {
+                Some(symbol) => write!(f, "{}+{:#x}", symbol.name, address - symbol.address)?,
+                None => write!(f, "<unknown>")?,
+            }
+        }
+        Ok(())
+    }
+}
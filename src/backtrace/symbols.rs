@@ -0,0 +1,65 @@
+//! Kernel symbol table
+//!
+//! The linker leaves `.kernel_symbols` zeroed. `scripts/embed-symbols.sh`
+//! fills it in the linked kernel with a `KSYM` header line followed by one
+//! `<address> <size> <name>` line per function, address and size in hex.
+//! Without that step nothing resolves and backtraces show bare addresses.
+
+/// Bytes reserved for the symbol table; the embedding script fails if it does not fit
+pub const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+
+/// First line of an embedded table
+const MAGIC: &[u8] = b"KSYM\n";
+
+// Mutable so the compiler cannot assume it still holds zeroes
+#[used]
+#[link_section = ".kernel_symbols"]
+static mut SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+/// The function containing an address
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// Start address of the function
+    pub address: usize,
+}
+
+/// Whether a symbol table was embedded into this kernel
+pub fn is_embedded() -> bool {
+    table().is_some()
+}
+
+/// Find the function containing `address`
+pub fn lookup(address: usize) -> Option<Symbol> {
+    // Lines are sorted by address, so the last one at or below `address` wins
+    let mut best = None;
+    for line in table()?.split(|&byte| byte == b'\n') {
+        let Some((symbol, size)) = parse_line(line) else {
+            continue;
+        };
+        if symbol.address > address {
+            break;
+        }
+        best = Some((symbol, size));
+    }
+    // Symbols without a size extend to the next one
+    best.filter(|&(symbol, size)| size == 0 || address < symbol.address + size)
+        .map(|(symbol, _)| symbol)
+}
+
+/// The embedded lines, without the header and padding
+fn table() -> Option<&'static [u8]> {
+    let table: &'static [u8; SYMBOL_TABLE_SIZE] = unsafe { &*core::ptr::addr_of!(SYMBOL_TABLE) };
+    let lines = table.strip_prefix(MAGIC)?;
+    let end = lines.iter().position(|&byte| byte == 0).unwrap_or(lines.len());
+    Some(&lines[..end])
+}
+
+fn parse_line(line: &'static [u8]) -> Option<(Symbol, usize)> {
+    let line = core::str::from_utf8(line).ok()?;
+    let mut fields = line.splitn(3, ' ');
+    let address = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let size = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let name = fields.next()?;
+    Some((Symbol { name, address }, size))
+}
@@ -5,6 +5,9 @@ use x86_64::instructions::port::Port;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
+/// IRQ line the PS/2 keyboard is wired to
+pub const KEYBOARD_IRQ: u8 = 1;
+
 /// PS/2 Keyboard driver
 pub struct Ps2Keyboard {
     initialized: bool,
@@ -114,12 +117,17 @@ impl Driver for Ps2Keyboard {
     fn init(&mut self) -> Result<(), DriverError> {
         // Reset and enable the keyboard
         self.send_command(0xF4); // Enable scanning command
+        crate::interrupts::register_irq_handler(KEYBOARD_IRQ, handle_keyboard_interrupt)
+            .map_err(|_| DriverError::ResourceAllocationFailed)?;
         
         self.initialized = true;
         Ok(())
     }
     
     fn deinit(&mut self) -> Result<(), DriverError> {
+        crate::interrupts::unregister_irq_handler(KEYBOARD_IRQ, handle_keyboard_interrupt)
+            .map_err(|_| DriverError::InterruptHandlingFailed)?;
+
         // Disable the keyboard
         self.send_command(0xF5); // Disable scanning command
         
@@ -138,7 +146,7 @@ lazy_static! {
 }
 
 /// Process a keyboard interrupt
-pub fn handle_keyboard_interrupt() {
+pub fn handle_keyboard_interrupt(_irq: u8) {
     let scancode = KEYBOARD.lock().read_scancode();
     
     // Determine if key was pressed or released
@@ -157,9 +165,4 @@ pub fn handle_keyboard_interrupt() {
             crate::println!("Key pressed: '{}'", ascii_char);
         }
     }
-    
-    // Send EOI to PIC
-    unsafe {
-        crate::interrupts::pic::PICS.lock().notify_end_of_interrupt(33);
-    }
 }
\ No newline at end of file
@@ -5,6 +5,9 @@ use x86_64::instructions::port::Port;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
+/// IRQ line the PIT is wired to
+pub const TIMER_IRQ: u8 = 0;
+
 /// PIT (Programmable Interval Timer) driver
 pub struct PitTimer {
     initialized: bool,
@@ -62,12 +65,17 @@ impl Driver for PitTimer {
     fn init(&mut self) -> Result<(), DriverError> {
         // Configure timer with 1000 Hz frequency (1ms intervals)
         self.configure(1000);
+        crate::interrupts::register_irq_handler(TIMER_IRQ, handle_timer_interrupt)
+            .map_err(|_| DriverError::ResourceAllocationFailed)?;
         
         self.initialized = true;
         Ok(())
     }
     
     fn deinit(&mut self) -> Result<(), DriverError> {
+        crate::interrupts::unregister_irq_handler(TIMER_IRQ, handle_timer_interrupt)
+            .map_err(|_| DriverError::InterruptHandlingFailed)?;
+
         // Reset timer
         unsafe {
             self.command_port.write(0x30);
@@ -142,12 +150,7 @@ impl SystemTimer {
 }
 
 /// Handle timer interrupt
-pub fn handle_timer_interrupt() {
+pub fn handle_timer_interrupt(_irq: u8) {
     // Increment system tick counter
     SYSTEM_TIMER.lock().handle_tick();
-    
-    // Send EOI to PIC
-    unsafe {
-        crate::interrupts::pic::PICS.lock().notify_end_of_interrupt(32);
-    }
 }
\ No newline at end of file
@@ -0,0 +1,281 @@
+//! Local APIC and I/O APIC support
+//!
+//! Replaces the 8259 PICs on machines that describe their APICs in the ACPI
+//! MADT. Legacy ISA IRQs are routed through the I/O APIC redirection
+//! entries to the same vectors the PICs used, so IRQ handlers do not care
+//! which controller delivered them. Once the APIC path is active the PICs
+//! are masked and every end-of-interrupt goes to the local APIC.
+
+use super::irq::IRQ_COUNT;
+use super::pic::{PICS, PIC_1_OFFSET};
+use super::InterruptError;
+use crate::acpi::madt::{Madt, Polarity, TriggerMode};
+use crate::memory::vmalloc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Vector the local APIC raises for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// IA32_APIC_BASE model-specific register
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Global enable bit in IA32_APIC_BASE
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+
+/// Software enable bit in the spurious interrupt vector register
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+/// Mask bit of local vector table and redirection entries
+const MASKED: u32 = 1 << 16;
+/// NMI delivery mode in a local vector table entry
+const DELIVERY_NMI: u32 = 0b100 << 8;
+/// Active-low polarity in a local vector table or redirection entry
+const ACTIVE_LOW: u32 = 1 << 13;
+/// Level trigger mode in a local vector table or redirection entry
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// I/O APIC registers
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Virtual address of the local APIC registers; zero while the PICs are in use
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// I/O APICs and where each legacy IRQ is routed
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+/// A mapped I/O APIC
+#[derive(Debug)]
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    /// Map the I/O APIC at `phys` serving GSIs from `gsi_base`
+    fn map(phys: PhysAddr, gsi_base: u32) -> Result<Self, InterruptError> {
+        let base = vmalloc::map_mmio("io apic", phys, 0x20).map_err(|_| InterruptError::ControllerNotInitialized)?;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    /// Whether this I/O APIC serves `gsi`
+    pub fn serves(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            (self.base + IOAPIC_REGSEL as u64).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IOAPIC_WINDOW as u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            (self.base + IOAPIC_REGSEL as u64).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IOAPIC_WINDOW as u64).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    /// Program the redirection entry for `gsi`
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // Mask first so the entry never fires half-written
+        self.write(register, MASKED);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    fn redirection_low(&self, gsi: u32) -> u32 {
+        self.read(IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base))
+    }
+
+    fn set_redirection_low(&self, gsi: u32, low: u32) {
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base), low);
+    }
+}
+
+/// Where a legacy IRQ is delivered
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    io_apic: usize,
+    gsi: u32,
+}
+
+struct Routing {
+    io_apics: Vec<IoApic>,
+    routes: [Option<Route>; IRQ_COUNT],
+}
+
+/// Switch interrupt delivery from the PICs to the APICs described by the MADT
+///
+/// Every legacy IRQ is routed to its usual vector on the boot processor.
+/// Lines that have handlers registered are unmasked; all others stay masked
+/// until a handler is registered. On failure the PICs stay in charge.
+/// `acpi::init` must have run first.
+pub fn init() -> Result<(), InterruptError> {
+    // CPUID leaf 1, EDX bit 9
+    let edx = crate::platform::x86_64::cpuid(1).edx;
+    if edx & (1 << 9) == 0 {
+        return Err(InterruptError::ControllerNotFound);
+    }
+    let madt = crate::acpi::tables()
+        .and_then(|tables| tables.madt.as_ref())
+        .ok_or(InterruptError::ControllerNotFound)?;
+    if madt.io_apics.is_empty() {
+        return Err(InterruptError::ControllerNotFound);
+    }
+
+    let local_apic = vmalloc::map_mmio("local apic", madt.local_apic_address, 0x400)
+        .map_err(|_| InterruptError::ControllerNotInitialized)?;
+    let io_apics = madt
+        .io_apics
+        .iter()
+        .map(|entry| IoApic::map(entry.address, entry.gsi_base))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    without_interrupts(|| {
+        enable_local_apic(local_apic, madt);
+        let apic_id = unsafe { read_register(local_apic, LAPIC_ID) } >> 24;
+
+        let mut routes = [None; IRQ_COUNT];
+        for (irq, route) in routes.iter_mut().enumerate() {
+            let (gsi, polarity, trigger) = madt.isa_irq_route(irq as u8);
+            let Some(index) = io_apics.iter().position(|io_apic| io_apic.serves(gsi)) else {
+                continue;
+            };
+            let mut low = (PIC_1_OFFSET as u32 + irq as u32) | MASKED;
+            if polarity == Polarity::ActiveLow {
+                low |= ACTIVE_LOW;
+            }
+            if trigger == TriggerMode::Level {
+                low |= LEVEL_TRIGGERED;
+            }
+            io_apics[index].set_redirection(gsi, ((apic_id as u64) << 56) | low as u64);
+            *route = Some(Route { io_apic: index, gsi });
+        }
+
+        *ROUTING.lock() = Some(Routing { io_apics, routes });
+        unsafe { PICS.lock().disable() };
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::Release);
+
+        for irq in 0..IRQ_COUNT as u8 {
+            if super::irq::handler_count(irq) > 0 {
+                set_irq_masked(irq, false);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Whether interrupts are delivered by the APICs rather than the PICs
+pub fn is_active() -> bool {
+    LOCAL_APIC.load(Ordering::Acquire) != 0
+}
+
+/// Signal end-of-interrupt to the local APIC
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::Acquire);
+    if base != 0 {
+        unsafe { write_register(VirtAddr::new(base), LAPIC_EOI, 0) };
+    }
+}
+
+/// Mask or unmask the redirection entry of legacy `irq`
+///
+/// Returns `false` if the IRQ is not routed through an I/O APIC.
+pub fn set_irq_masked(irq: u8, masked: bool) -> bool {
+    let routing = ROUTING.lock();
+    let Some(routing) = routing.as_ref() else {
+        return false;
+    };
+    let Some(route) = routing.routes.get(irq as usize).copied().flatten() else {
+        return false;
+    };
+    let io_apic = &routing.io_apics[route.io_apic];
+    let low = io_apic.redirection_low(route.gsi);
+    io_apic.set_redirection_low(route.gsi, if masked { low | MASKED } else { low & !MASKED });
+    true
+}
+
+/// Install the handler for the local APIC's spurious vector
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+}
+
+/// Spurious local APIC interrupts are not in service and must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    super::stats::record_spurious(SPURIOUS_VECTOR, super::stats::timestamp());
+}
+
+/// Enable the local APIC of this processor and program its local interrupts
+fn enable_local_apic(base: VirtAddr, madt: &Madt) {
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+
+        write_register(base, LAPIC_TASK_PRIORITY, 0);
+        write_register(base, LAPIC_LVT_TIMER, MASKED);
+        write_register(base, LAPIC_LVT_ERROR, MASKED);
+        write_register(base, LAPIC_LVT_LINT0, MASKED);
+        write_register(base, LAPIC_LVT_LINT1, MASKED);
+
+        // Pins the firmware wires to NMI, typically LINT1; entries naming
+        // another processor are left to that processor
+        let apic_id = read_register(base, LAPIC_ID) >> 24;
+        let processor_uid = madt
+            .processors
+            .iter()
+            .find(|processor| processor.apic_id == apic_id)
+            .map(|processor| processor.processor_uid);
+        for nmi in &madt.local_apic_nmis {
+            if nmi.processor_uid.is_some() && nmi.processor_uid != processor_uid {
+                continue;
+            }
+            let register = match nmi.lint {
+                0 => LAPIC_LVT_LINT0,
+                1 => LAPIC_LVT_LINT1,
+                _ => continue,
+            };
+            let mut entry = DELIVERY_NMI;
+            if nmi.polarity == Polarity::ActiveLow {
+                entry |= ACTIVE_LOW;
+            }
+            write_register(base, register, entry);
+        }
+
+        write_register(base, LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+}
+
+unsafe fn read_register(base: VirtAddr, offset: usize) -> u32 {
+    (base + offset as u64).as_ptr::<u32>().read_volatile()
+}
+
+unsafe fn write_register(base: VirtAddr, offset: usize, value: u32) {
+    (base + offset as u64).as_mut_ptr::<u32>().write_volatile(value);
+}
@@ -0,0 +1,235 @@
+//! Crash reports for CPU exceptions
+//!
+//! Every fault or abort produces the same report: vector and name, the
+//! decoded error code, the interrupted register state, the control registers
+//! and the process that was running, followed by a backtrace of the
+//! interrupted code. A report goes to both the VGA console and serial. The
+//! machine or at least the faulting process is going down, so console locks
+//! held by the interrupted code are broken instead of waited on.
+//!
+//! Breakpoint and debug traps resume the interrupted code, so they get a
+//! shorter trap report instead, which skips any console that is locked.
+
+use crate::backtrace::Backtrace;
+use crate::process::ProcessId;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+/// Print to VGA and serial, breaking any console lock held by the interrupted code
+macro_rules! crash_println {
+    ($($arg:tt)*) => ($crate::interrupts::crash::emit(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Mnemonic and name of each architectural exception vector
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("#DE", "DIVIDE ERROR"),
+    ("#DB", "DEBUG"),
+    ("NMI", "NON-MASKABLE INTERRUPT"),
+    ("#BP", "BREAKPOINT"),
+    ("#OF", "OVERFLOW"),
+    ("#BR", "BOUND RANGE EXCEEDED"),
+    ("#UD", "INVALID OPCODE"),
+    ("#NM", "DEVICE NOT AVAILABLE"),
+    ("#DF", "DOUBLE FAULT"),
+    ("", "COPROCESSOR SEGMENT OVERRUN"),
+    ("#TS", "INVALID TSS"),
+    ("#NP", "SEGMENT NOT PRESENT"),
+    ("#SS", "STACK-SEGMENT FAULT"),
+    ("#GP", "GENERAL PROTECTION FAULT"),
+    ("#PF", "PAGE FAULT"),
+    ("", "RESERVED"),
+    ("#MF", "X87 FLOATING-POINT EXCEPTION"),
+    ("#AC", "ALIGNMENT CHECK"),
+    ("#MC", "MACHINE CHECK"),
+    ("#XM", "SIMD FLOATING-POINT EXCEPTION"),
+    ("#VE", "VIRTUALIZATION EXCEPTION"),
+    ("#CP", "CONTROL PROTECTION EXCEPTION"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("", "RESERVED"),
+    ("#HV", "HYPERVISOR INJECTION EXCEPTION"),
+    ("#VC", "VMM COMMUNICATION EXCEPTION"),
+    ("#SX", "SECURITY EXCEPTION"),
+    ("", "RESERVED"),
+];
+
+#[doc(hidden)]
+pub fn emit(args: fmt::Arguments) {
+    unsafe {
+        if crate::vga_buffer::WRITER.is_locked() {
+            crate::vga_buffer::WRITER.force_unlock();
+        }
+        if crate::serial::SERIAL1.is_locked() {
+            crate::serial::SERIAL1.force_unlock();
+        }
+    }
+    crate::vga_buffer::_print(args);
+    crate::serial::_print(args);
+}
+
+/// Print to VGA and serial, skipping any console the interrupted code holds
+fn try_emit(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+        let _ = writer.write_fmt(args);
+    }
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        let _ = serial.write_fmt(args);
+    }
+}
+
+/// Print to VGA and serial without breaking console locks
+macro_rules! trap_println {
+    ($($arg:tt)*) => (try_emit(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Name of exception `vector`
+pub fn exception_name(vector: u8) -> &'static str {
+    EXCEPTIONS.get(vector as usize).map_or("UNKNOWN", |&(_, name)| name)
+}
+
+/// Print the crash report for exception `vector`
+pub fn report(vector: u8, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    crash_println!("==================== KERNEL CRASH ====================");
+    report_registers(vector, stack_frame, error_code);
+
+    // The interrupted code may hold the scheduler lock
+    match crate::process::SCHEDULER.try_lock() {
+        Some(scheduler) => match scheduler.current_process() {
+            Some(process) => crash_println!("Process: {} (PID: {})", process.name, process.id),
+            None => crash_println!("Process: <none>"),
+        },
+        None => crash_println!("Process: <scheduler locked>"),
+    }
+    crash_println!("{}", Backtrace::interrupted(stack_frame));
+    crash_println!("======================================================");
+}
+
+/// Print the report for trap `vector`, after which the interrupted code resumes
+pub fn report_trap(vector: u8, stack_frame: &InterruptStackFrame) {
+    let (mnemonic, name) = EXCEPTIONS.get(vector as usize).copied().unwrap_or(("", "UNKNOWN"));
+    trap_println!("------------------------ TRAP ------------------------");
+    trap_println!("EXCEPTION: {} {} (vector {})", mnemonic, name, vector);
+    trap_println!(
+        "RIP: {:#018x}  RSP: {:#018x}  RFLAGS: {:#018x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.cpu_flags
+    );
+    trap_println!("{}", Backtrace::interrupted(stack_frame));
+    trap_println!("------------------------------------------------------");
+}
+
+/// Print the report for exception `vector` raised by process `pid`, which has been terminated
+pub fn report_process_fault(
+    pid: ProcessId,
+    process_name: &str,
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+) {
+    crash_println!("==================== PROCESS FAULT ===================");
+    report_registers(vector, stack_frame, error_code);
+    crash_println!("Process: {} (PID: {}) terminated", process_name, pid);
+    crash_println!("{}", Backtrace::interrupted(stack_frame));
+    crash_println!("======================================================");
+}
+
+fn report_registers(vector: u8, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    let (mnemonic, name) = EXCEPTIONS.get(vector as usize).copied().unwrap_or(("", "UNKNOWN"));
+    crash_println!("EXCEPTION: {} {} (vector {})", mnemonic, name, vector);
+    if let Some(code) = error_code {
+        crash_println!("Error code: {:#x} ({})", code, DecodedErrorCode { vector, code });
+    }
+    crash_println!(
+        "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#018x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags
+    );
+    crash_println!(
+        "RSP: {:#018x}  SS: {:#06x}",
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment
+    );
+    crash_println!(
+        "CR0: {:#018x}  CR2: {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64()
+    );
+    crash_println!(
+        "CR3: {:#018x}  CR4: {:#018x}",
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
+
+/// Stop the machine after a crash report
+pub fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::hlt_loop()
+}
+
+/// Human-readable form of an exception error code
+struct DecodedErrorCode {
+    vector: u8,
+    code: u64,
+}
+
+impl fmt::Display for DecodedErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.vector {
+            // Invalid TSS, segment not present, stack-segment and general protection
+            10..=13 if self.code == 0 => write!(f, "no selector"),
+            10..=13 => {
+                let table = match (self.code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                let external = if self.code & 1 != 0 { ", external event" } else { "" };
+                write!(f, "{} index {}{}", table, (self.code >> 3) & 0x1fff, external)
+            }
+            14 => {
+                let flags = PageFaultErrorCode::from_bits_truncate(self.code);
+                let access = if flags.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                    "instruction fetch"
+                } else if flags.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                    "write"
+                } else {
+                    "read"
+                };
+                let cause = if flags.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                    "protection violation"
+                } else {
+                    "page not present"
+                };
+                let mode = if flags.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+                write!(f, "{} in {} mode, {}", access, mode, cause)?;
+                if flags.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                    write!(f, ", reserved bit set in page table")?;
+                }
+                Ok(())
+            }
+            21 => {
+                let cause = match self.code & 0x7fff {
+                    1 => "near return address mismatch",
+                    2 => "far return address mismatch",
+                    3 => "missing ENDBRANCH",
+                    4 => "invalid shadow stack restore token",
+                    5 => "invalid shadow stack busy token",
+                    _ => "unknown cause",
+                };
+                let enclave = if self.code & (1 << 15) != 0 { " in enclave" } else { "" };
+                write!(f, "{}{}", cause, enclave)
+            }
+            // Double fault and alignment check always push zero
+            8 | 17 => write!(f, "always zero"),
+            _ => write!(f, "raw"),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Global descriptor table and task state segment
+//!
+//! The GDT holds flat kernel and user code and data segments, in the order
+//! `syscall`/`sysret` expect, and the TSS.
+//!
+//! The TSS provides stacks that are known to be good. A kernel stack
+//! overflow faults on a guard page, and the CPU cannot push the page fault
+//! frame onto the overflowed stack, so the resulting double fault is handled
+//! on a stack of its own. NMIs and machine checks can arrive at any
+//! instruction, including while the stack pointer is being switched, so
+//! they get one as well.
+//!
+//! These stacks are kernel stacks from `memory::stack`, so overflowing one
+//! hits a guard page instead of the memory below it. Memory management must
+//! be initialized before `init` runs.
+
+use crate::memory::KernelStack;
+use lazy_static::lazy_static;
+use spin::Once;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+
+/// Interrupt stack table slot used by the double fault handler
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Interrupt stack table slot used by the NMI handler
+pub const NMI_IST_INDEX: u16 = 1;
+/// Interrupt stack table slot used by the machine check handler
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Stacks the TSS points at, with the name reported when one overflows
+static STACKS: Once<[(&str, KernelStack); 4]> = Once::new();
+
+/// Index of the ring 0 stack loaded when an interrupt arrives in user mode
+const PRIVILEGE_STACK: usize = 3;
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let stacks = STACKS.call_once(|| {
+            let allocate = |name| (name, KernelStack::allocate().expect("failed to allocate a TSS stack"));
+            [
+                allocate("double fault handler"),
+                allocate("NMI handler"),
+                allocate("machine check handler"),
+                allocate("user mode entry"),
+            ]
+        });
+        let mut tss = TaskStateSegment::new();
+        for index in [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+            tss.interrupt_stack_table[index as usize] = stacks[index as usize].1.top();
+        }
+        tss.privilege_stack_table[0] = stacks[PRIVILEGE_STACK].1.top();
+        tss
+    };
+}
+
+/// Name of the TSS stack in kernel stack slot `slot`, if it is one
+pub fn stack_owner(slot: usize) -> Option<&'static str> {
+    let stacks = STACKS.get()?;
+    stacks.iter().find(|(_, stack)| stack.slot() == slot).map(|&(name, _)| name)
+}
+
+/// Segment selectors of the kernel GDT
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        // `sysret` expects user data directly before user code
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        (
+            gdt,
+            Selectors {
+                kernel_code,
+                kernel_data,
+                user_data,
+                user_code,
+                tss,
+            },
+        )
+    };
+}
+
+/// Load the GDT and TSS and reload the segment registers
+pub fn init() {
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.kernel_code);
+        SS::set_reg(GDT.1.kernel_data);
+        DS::set_reg(GDT.1.kernel_data);
+        ES::set_reg(GDT.1.kernel_data);
+        load_tss(GDT.1.tss);
+    }
+}
+
+/// Selectors for the segments in the GDT
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
@@ -0,0 +1,158 @@
+//! Hardware IRQ dispatch
+//!
+//! Every IRQ line has an entry stub in the IDT that forwards to `dispatch`.
+//! Drivers hook a line at runtime with `register_irq_handler`. A line may be
+//! shared by several devices; all of its handlers run on every interrupt, so
+//! a handler on a shared line must check that its own device raised it.
+//! The dispatcher acknowledges the interrupt controller, the PICs or the
+//! local APIC, once the handlers have run, so handlers never send an
+//! end-of-interrupt themselves. Spurious IRQ 7 and IRQ 15 from the PICs are
+//! counted and dropped without running any handler.
+
+use super::pic::{PICS, PIC_1_OFFSET};
+use super::{apic, stats, InterruptError};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// Number of legacy IRQ lines
+pub const IRQ_COUNT: usize = 16;
+/// Maximum number of handlers sharing one line
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// Called with the IRQ number each time the line fires
+pub type IrqHandler = fn(irq: u8);
+
+type Line = [Option<IrqHandler>; MAX_SHARED_HANDLERS];
+
+/// Handlers per line; a fixed table so dispatch never touches the heap
+static HANDLERS: Mutex<[Line; IRQ_COUNT]> = Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_COUNT]);
+
+/// Add `handler` to line `irq`, unmasking the line if it was unused
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), InterruptError> {
+    let index = line_index(irq)?;
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[index];
+        if line.iter().flatten().any(|&existing| same_handler(existing, handler)) {
+            return Err(InterruptError::HandlerRegistrationFailed);
+        }
+        let slot = line
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(InterruptError::HandlerRegistrationFailed)?;
+        *slot = Some(handler);
+        set_masked(irq, false);
+        Ok(())
+    })
+}
+
+/// Remove `handler` from line `irq`, masking the line once no handler is left
+pub fn unregister_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), InterruptError> {
+    let index = line_index(irq)?;
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[index];
+        let slot = line
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|existing| same_handler(existing, handler)))
+            .ok_or(InterruptError::HandlerNotRegistered(irq))?;
+        *slot = None;
+        if line.iter().all(Option::is_none) {
+            set_masked(irq, true);
+        }
+        Ok(())
+    })
+}
+
+/// Number of handlers registered on line `irq`
+pub fn handler_count(irq: u8) -> usize {
+    line_index(irq).map_or(0, |index| {
+        without_interrupts(|| HANDLERS.lock()[index].iter().flatten().count())
+    })
+}
+
+/// Point the IDT entries of every IRQ line at the dispatcher
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (irq, stub) in STUBS.iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + irq].set_handler_fn(*stub);
+    }
+}
+
+/// Run every handler registered on `irq`, then acknowledge the interrupt
+fn dispatch(irq: u8) {
+    let vector = PIC_1_OFFSET + irq;
+    let start = stats::timestamp();
+    if !apic::is_active() {
+        let mut pics = PICS.lock();
+        if unsafe { pics.is_spurious(vector) } {
+            unsafe { pics.notify_spurious(vector) };
+            stats::record_spurious(vector, start);
+            return;
+        }
+    }
+
+    // Copy the line out so handlers may register or unregister handlers
+    let line = HANDLERS.lock()[irq as usize];
+    for handler in line.iter().flatten() {
+        handler(irq);
+    }
+    stats::record(vector, start, stats::timestamp());
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+    }
+}
+
+fn line_index(irq: u8) -> Result<usize, InterruptError> {
+    match irq as usize {
+        index if index < IRQ_COUNT => Ok(index),
+        _ => Err(InterruptError::InvalidInterrupt(irq)),
+    }
+}
+
+fn same_handler(a: IrqHandler, b: IrqHandler) -> bool {
+    a as usize == b as usize
+}
+
+/// Mask or unmask `irq` at whichever controller delivers it
+///
+/// On the PICs, the cascade line to the secondary PIC is never masked.
+fn set_masked(irq: u8, masked: bool) {
+    if apic::is_active() {
+        apic::set_irq_masked(irq, masked);
+        return;
+    }
+    let mut pics = PICS.lock();
+    let (mut primary, mut secondary) = unsafe { pics.read_masks() };
+    let (mask, bit) = if irq < 8 { (&mut primary, irq) } else { (&mut secondary, irq - 8) };
+    if masked {
+        *mask |= 1 << bit;
+    } else {
+        *mask &= !(1 << bit);
+    }
+    if irq >= 8 {
+        primary &= !(1 << 2);
+    }
+    unsafe { pics.write_masks(primary, secondary) };
+}
+
+macro_rules! irq_stubs {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3,
+    irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11,
+    irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+}
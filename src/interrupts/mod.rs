@@ -1,7 +1,18 @@
+pub mod apic;
+#[macro_use]
+pub mod crash;
+pub mod gdt;
+pub mod irq;
 pub mod pic;
+pub mod stats;
 
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{println, serial_println};
+use x86_64::VirtAddr;
+use crate::memory::stack::{self, GuardPage};
+use crate::process::{self, user, Fault};
+
+pub use irq::{register_irq_handler, unregister_irq_handler, IrqHandler};
+pub use stats::{vector_stats, VectorStats};
 
 /// Interrupt handling errors
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,8 +21,12 @@ pub enum InterruptError {
     InvalidInterrupt(u8),
     /// Handler registration failed
     HandlerRegistrationFailed,
+    /// The handler is not registered on this IRQ line
+    HandlerNotRegistered(u8),
     /// Interrupt controller not initialized
     ControllerNotInitialized,
+    /// The machine does not describe a usable interrupt controller
+    ControllerNotFound,
 }
 
 impl core::fmt::Display for InterruptError {
@@ -19,20 +34,94 @@ impl core::fmt::Display for InterruptError {
         match self {
             InterruptError::InvalidInterrupt(irq) => write!(f, "Invalid interrupt number: {}", irq),
             InterruptError::HandlerRegistrationFailed => write!(f, "Failed to register interrupt handler"),
+            InterruptError::HandlerNotRegistered(irq) => write!(f, "Handler not registered on IRQ {}", irq),
             InterruptError::ControllerNotInitialized => write!(f, "Interrupt controller not initialized"),
+            InterruptError::ControllerNotFound => write!(f, "No usable interrupt controller found"),
         }
     }
 }
 
+/// Define a handler that reports exception `$vector` and halts
+macro_rules! fatal_exception {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            stats::record_fatal($vector);
+            crash::report($vector, &stack_frame, None);
+            crash::halt();
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
+            stats::record_fatal($vector);
+            crash::report($vector, &stack_frame, Some(error_code));
+            crash::halt();
+        }
+    };
+}
+
+/// Define a handler that terminates the process that raised exception
+/// `$vector`, or reports it and halts if the kernel raised it
+macro_rules! process_exception {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+            let start = stats::timestamp();
+            let terminated = terminate_faulting_process($vector, &mut stack_frame, None, None);
+            stats::record($vector, start, stats::timestamp());
+            if !terminated {
+                crash::report($vector, &stack_frame, None);
+                crash::halt();
+            }
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame, error_code: u64) {
+            let start = stats::timestamp();
+            let terminated = terminate_faulting_process($vector, &mut stack_frame, Some(error_code), None);
+            stats::record($vector, start, stats::timestamp());
+            if !terminated {
+                crash::report($vector, &stack_frame, Some(error_code));
+                crash::halt();
+            }
+        }
+    };
+}
+
 lazy_static::lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        idt[32].set_handler_fn(timer_interrupt_handler);
-        idt[33].set_handler_fn(keyboard_interrupt_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.cp_protection_exception.set_handler_fn(control_protection_handler);
+        idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        irq::install(&mut idt);
+        apic::install(&mut idt);
         idt
     };
 }
@@ -43,54 +132,159 @@ pub fn init_idt() {
 
 // Exception handlers
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    // Breakpoints are traps; report them and carry on
+    let start = stats::timestamp();
+    crash::report_trap(3, &stack_frame);
+    stats::record(3, start, stats::timestamp());
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let start = stats::timestamp();
+    crash::report_trap(1, &stack_frame);
+    stats::record(1, start, stats::timestamp());
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    serial_println!("EXCEPTION: PAGE FAULT");
-    loop {
-        x86_64::instructions::hlt();
+    // Faults inside registered memory areas are resolved by demand paging
+    let start = stats::timestamp();
+    let accessed_address = Cr2::read();
+    if crate::memory::handle_page_fault(accessed_address, error_code).is_ok() {
+        stats::record(14, start, stats::timestamp());
+        return;
     }
+
+    // A guard page hit means kernel memory is already overrun
+    let terminated = !report_guard_page_hit(accessed_address)
+        && terminate_faulting_process(14, &mut stack_frame, Some(error_code.bits()), Some(accessed_address));
+    stats::record(14, start, stats::timestamp());
+    if terminated {
+        return;
+    }
+    crash::report(14, &stack_frame, Some(error_code.bits()));
+    crash::halt();
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
+extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    println!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
-    serial_println!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
-    loop {
-        x86_64::instructions::hlt();
+    error_code: u64,
+) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // A page fault on a stack guard page cannot push its frame and escalates
+    // to a double fault; the guard address is still in CR2. CR2 keeps the
+    // last page fault address however the double fault arose, so it only
+    // counts as an overflow if the interrupted stack pointer is next to it.
+    stats::record_fatal(8);
+    let addr = Cr2::read();
+    if stack_frame.stack_pointer.as_u64().abs_diff(addr.as_u64()) <= crate::memory::PAGE_SIZE as u64 {
+        report_guard_page_hit(addr);
     }
+    crash::report(8, &stack_frame, Some(error_code));
+    crash::halt();
 }
 
-extern "x86-interrupt" fn divide_error_handler(
-    stack_frame: InterruptStackFrame,
-) {
-    println!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
-    serial_println!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
-    loop {
-        x86_64::instructions::hlt();
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    // NMIs on this platform signal hardware errors; system control port B says which
+    stats::record_fatal(2);
+    let status: u8 = unsafe { Port::new(0x61).read() };
+    if status & 0x80 != 0 {
+        crash_println!("NMI reason: memory parity error");
+    } else if status & 0x40 != 0 {
+        crash_println!("NMI reason: I/O channel check");
+    }
+    crash::report(2, &stack_frame, None);
+    crash::halt();
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    stats::record_fatal(18);
+    crash::report(18, &stack_frame, None);
+    crash::halt();
+}
+
+/// Terminate the process that raised exception `vector`
+///
+/// Only faults raised in ring 3 by a process entered through
+/// `process::user::run_user` belong to a process; for anything else this
+/// returns `false` and the caller treats the exception as fatal. Otherwise
+/// `stack_frame` is rewritten so that returning from the handler abandons
+/// the faulting code and returns from `run_user`, which switches to the
+/// next process.
+fn terminate_faulting_process(
+    vector: u8,
+    stack_frame: &mut InterruptStackFrame,
+    error_code: Option<u64>,
+    address: Option<VirtAddr>,
+) -> bool {
+    if stack_frame.code_segment & 0x3 != 3 {
+        return false;
     }
+    let fault = Fault {
+        vector,
+        instruction_pointer: stack_frame.instruction_pointer,
+        error_code,
+        address,
+    };
+    let Some((pid, name, resume)) = process::terminate_current(fault) else {
+        return false;
+    };
+    crash::report_process_fault(pid, &name, vector, stack_frame, error_code);
+    user::abandon(stack_frame, resume);
+    true
 }
 
-// Hardware interrupt handlers
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // Handle timer interrupt using our driver
-    crate::drivers::timer::handle_timer_interrupt();
+/// Report an access to a guard page; returns `false` if `addr` is not on one
+fn report_guard_page_hit(addr: VirtAddr) -> bool {
+    match stack::guard_page(addr) {
+        Some(GuardPage::KernelStack { slot }) => {
+            // The overflowing code may hold the scheduler lock
+            let scheduler = crate::process::SCHEDULER.try_lock();
+            let task = gdt::stack_owner(slot)
+                .or_else(|| {
+                    scheduler
+                        .as_ref()
+                        .and_then(|scheduler| scheduler.kernel_stack_owner(slot))
+                        .map(|process| process.name.as_str())
+                })
+                .unwrap_or("<unknown task>");
+            crash_println!("EXCEPTION: stack overflow in {}", task);
+            true
+        }
+        Some(GuardPage::BootStack) => {
+            crash_println!("EXCEPTION: stack overflow in the boot stack");
+            true
+        }
+        Some(GuardPage::Region { name }) => {
+            crash_println!("EXCEPTION: overrun past the end of {} at {:?}", name, addr);
+            true
+        }
+        None => false,
+    }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // Handle keyboard interrupt using our driver
-    crate::drivers::keyboard::handle_keyboard_interrupt();
-}
\ No newline at end of file
+process_exception!(divide_error_handler, 0);
+process_exception!(overflow_handler, 4);
+process_exception!(bound_range_exceeded_handler, 5);
+process_exception!(invalid_opcode_handler, 6);
+process_exception!(device_not_available_handler, 7);
+process_exception!(segment_not_present_handler, 11, error_code);
+process_exception!(stack_segment_fault_handler, 12, error_code);
+process_exception!(general_protection_fault_handler, 13, error_code);
+process_exception!(x87_floating_point_handler, 16);
+process_exception!(alignment_check_handler, 17, error_code);
+process_exception!(simd_floating_point_handler, 19);
+process_exception!(control_protection_handler, 21, error_code);
+
+// Faults that reflect the state of the machine rather than the running code
+fatal_exception!(invalid_tss_handler, 10, error_code);
+fatal_exception!(virtualization_handler, 20);
+fatal_exception!(hv_injection_handler, 28);
+fatal_exception!(vmm_communication_handler, 29, error_code);
+fatal_exception!(security_exception_handler, 30, error_code);
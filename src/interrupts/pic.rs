@@ -3,9 +3,9 @@ use x86_64::instructions::port::Port;
 use core::iter::Iterator;
 
 /// The offset for the first PIC (master)
-const PIC_1_OFFSET: u8 = 32;
+pub const PIC_1_OFFSET: u8 = 32;
 /// The offset for the second PIC (slave)
-const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 /// Command sent to begin PIC initialization.
 const CMD_INIT: u8 = 0x11;
@@ -40,6 +40,18 @@ impl Pic {
     unsafe fn end_of_interrupt(&mut self) {
         self.command.write(CMD_END_OF_INTERRUPT);
     }
+
+    /// Read the interrupt service register: the lines being handled.
+    unsafe fn read_isr(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+
+    /// Read the interrupt request register: the lines waiting to be handled.
+    unsafe fn read_irr(&mut self) -> u8 {
+        self.command.write(CMD_READ_IRR);
+        self.command.read()
+    }
 }
 
 /// A pair of PICs (primary and secondary).
@@ -110,6 +122,37 @@ impl ChainedPics {
         self.pics[1].data.write(0xFF);
     }
 
+    /// Returns the interrupt service registers, secondary PIC in the high byte
+    pub unsafe fn read_isr(&mut self) -> u16 {
+        (self.pics[1].read_isr() as u16) << 8 | self.pics[0].read_isr() as u16
+    }
+
+    /// Returns the interrupt request registers, secondary PIC in the high byte
+    pub unsafe fn read_irr(&mut self) -> u16 {
+        (self.pics[1].read_irr() as u16) << 8 | self.pics[0].read_irr() as u16
+    }
+
+    /// Is this a spurious interrupt?
+    ///
+    /// When a request goes away before the CPU acknowledges it, a PIC raises
+    /// its lowest-priority line, IRQ 7 or IRQ 15, without putting it in service.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        self.pics
+            .iter_mut()
+            .any(|pic| interrupt_id == pic.offset + 7 && pic.read_isr() & 0x80 == 0)
+    }
+
+    /// Acknowledge a spurious interrupt.
+    ///
+    /// The PIC that raised it has nothing in service and must not get an
+    /// end-of-interrupt. A spurious IRQ 15 still arrived through a genuine
+    /// cascade request on the primary PIC, which does need one.
+    pub unsafe fn notify_spurious(&mut self, interrupt_id: u8) {
+        if self.pics[1].handles_interrupt(interrupt_id) {
+            self.pics[0].end_of_interrupt();
+        }
+    }
+
     /// Do we handle this interrupt?
     pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
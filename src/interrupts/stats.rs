@@ -0,0 +1,128 @@
+//! Per-vector interrupt statistics
+//!
+//! Counts how often each vector fires, when it last did, and the longest
+//! time its handlers took. Times are read from the time stamp counter, so
+//! they are in CPU cycles. The counters are atomics and never lock, which
+//! keeps recording safe in any interrupt context.
+//!
+//! Exceptions are counted like IRQs. Those that halt the machine are
+//! recorded on entry with no latency, since their handlers never finish.
+
+use super::apic;
+use super::pic::PICS;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of interrupt vectors
+pub const VECTOR_COUNT: usize = 256;
+
+/// Snapshot of the counters of one vector
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VectorStats {
+    /// Interrupts handled
+    pub count: u64,
+    /// Interrupts no device raised; these are not included in `count`
+    pub spurious: u64,
+    /// Time stamp counter when the vector last fired, or zero
+    pub last_timestamp: u64,
+    /// Longest time spent in the handlers, in cycles
+    pub max_latency: u64,
+}
+
+struct Counters {
+    count: AtomicU64,
+    spurious: AtomicU64,
+    last_timestamp: AtomicU64,
+    max_latency: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            count: AtomicU64::new(0),
+            spurious: AtomicU64::new(0),
+            last_timestamp: AtomicU64::new(0),
+            max_latency: AtomicU64::new(0),
+        }
+    }
+}
+
+static COUNTERS: [Counters; VECTOR_COUNT] = [const { Counters::new() }; VECTOR_COUNT];
+
+/// Current value of the time stamp counter
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Record an interrupt on `vector` whose handlers ran from `start` to `end`
+pub(super) fn record(vector: u8, start: u64, end: u64) {
+    let counters = &COUNTERS[vector as usize];
+    counters.count.fetch_add(1, Ordering::Relaxed);
+    counters.last_timestamp.store(start, Ordering::Relaxed);
+    counters.max_latency.fetch_max(end.saturating_sub(start), Ordering::Relaxed);
+}
+
+/// Record an exception on `vector` whose handler halts the machine
+pub(super) fn record_fatal(vector: u8) {
+    let now = timestamp();
+    record(vector, now, now);
+}
+
+/// Record a spurious interrupt on `vector` at `timestamp`
+pub(super) fn record_spurious(vector: u8, timestamp: u64) {
+    let counters = &COUNTERS[vector as usize];
+    counters.spurious.fetch_add(1, Ordering::Relaxed);
+    counters.last_timestamp.store(timestamp, Ordering::Relaxed);
+}
+
+/// Counters of `vector`
+pub fn vector_stats(vector: u8) -> VectorStats {
+    let counters = &COUNTERS[vector as usize];
+    VectorStats {
+        count: counters.count.load(Ordering::Relaxed),
+        spurious: counters.spurious.load(Ordering::Relaxed),
+        last_timestamp: counters.last_timestamp.load(Ordering::Relaxed),
+        max_latency: counters.max_latency.load(Ordering::Relaxed),
+    }
+}
+
+/// Counters of every vector that has fired, in vector order
+pub fn active_vectors() -> impl Iterator<Item = (u8, VectorStats)> {
+    (0..VECTOR_COUNT)
+        .map(|vector| (vector as u8, vector_stats(vector as u8)))
+        .filter(|(_, stats)| stats.count > 0 || stats.spurious > 0)
+}
+
+/// Clear every counter
+pub fn reset() {
+    for counters in COUNTERS.iter() {
+        counters.count.store(0, Ordering::Relaxed);
+        counters.spurious.store(0, Ordering::Relaxed);
+        counters.last_timestamp.store(0, Ordering::Relaxed);
+        counters.max_latency.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Print the counters of every vector that has fired over serial
+pub fn dump() {
+    crate::serial_println!("Interrupt statistics (TSC {}):", timestamp());
+    crate::serial_println!("  vector        count   spurious  last timestamp  max latency");
+    for (vector, stats) in active_vectors() {
+        crate::serial_println!(
+            "  {:>6} {:>12} {:>10} {:>15} {:>12}",
+            vector,
+            stats.count,
+            stats.spurious,
+            stats.last_timestamp,
+            stats.max_latency
+        );
+    }
+    if !apic::is_active() {
+        let (pending, in_service) = without_interrupts(|| {
+            let mut pics = PICS.lock();
+            unsafe { (pics.read_irr(), pics.read_isr()) }
+        });
+        crate::serial_println!("  PIC requests pending: {:#06x}, in service: {:#06x}", pending, in_service);
+    }
+}
@@ -5,6 +5,8 @@
 
 extern crate alloc;
 
+pub mod acpi;
+pub mod backtrace;
 pub mod serial;
 pub mod vga_buffer;
 pub mod memory;
@@ -14,11 +16,11 @@ pub mod drivers;
 pub mod process;
 
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
+use memory::KernelHeap;
 
 /// The global allocator for the kernel heap.
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap = KernelHeap::empty();
 
 /// A function to halt the CPU indefinitely
 pub fn hlt_loop() -> ! {
@@ -27,16 +29,26 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// Called when a kernel allocation fails even after memory reclaim
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("Allocation error: {:?}", layout)
+    panic!(
+        "Out of memory after reclaim: {:?} (heap {} of {} bytes used, limit {})",
+        layout,
+        ALLOCATOR.used(),
+        ALLOCATOR.size(),
+        ALLOCATOR.limit()
+    )
 }
 
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    let backtrace = backtrace::Backtrace::capture();
     println!("KERNEL PANIC: {}", info);
     serial_println!("KERNEL PANIC: {}", info);
+    println!("{}", backtrace);
+    serial_println!("{}", backtrace);
     
     hlt_loop();
 }
\ No newline at end of file
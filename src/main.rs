@@ -1,38 +1,50 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
 
 extern crate alloc;
 
-mod vga_buffer;
-mod serial;
-mod memory;
-mod interrupts;
-mod platform;
-mod drivers;
-mod process;
-
+use kewve_os::{acpi, drivers, interrupts, memory, platform, process, println, serial_println};
+use bootloader::{entry_point, BootInfo};
 use uart_16550::SerialPort;
 use alloc::boxed::Box;
-use crate::drivers::Driver;
+use kewve_os::drivers::Driver;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Initialize VGA buffer
     println!("Kewve OS is booting...");
     
-    // Initialize memory management
-    // TODO: Get memory map from bootloader and initialize proper memory management
-    // For now, use simplified heap initialization
-    memory::init_heap()
-        .expect("Heap initialization failed");
+    // Initialize memory management from the bootloader memory map
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init_page_table(physical_memory_offset) };
+    if let Err(error) = unsafe {
+        memory::init_memory_management(&boot_info.memory_map, physical_memory_offset, mapper)
+    } {
+        panic!("Memory management initialization failed: {}", error);
+    }
     println!("Heap initialized successfully");
+    let stats = memory::get_memory_stats();
+    println!("Memory: {} KiB free of {} KiB", stats.free_memory / 1024, stats.total_memory / 1024);
     
     // Initialize platform
     let platform_name = platform::detect_platform().unwrap_or("unknown");
     println!("Detected platform: {}", platform_name);
+    match acpi::init() {
+        Ok(tables) => println!(
+            "ACPI {} tables found: {} processor(s), {} PCI config region(s)",
+            if tables.revision >= 2 { "2.0+" } else { "1.0" },
+            tables.processor_count(),
+            tables.pci_config_regions.len()
+        ),
+        Err(error) => println!("ACPI unavailable: {}", error),
+    }
     
     // Initialize interrupts
+    interrupts::gdt::init();
+    println!("GDT initialized successfully");
+    
     interrupts::init_idt();
     println!("IDT initialized successfully");
     
@@ -42,6 +54,12 @@ pub extern "C" fn _start() -> ! {
     }
     println!("PIC initialized successfully");
     
+    // Prefer the APICs when the firmware describes them
+    match interrupts::apic::init() {
+        Ok(()) => println!("APIC initialized, legacy PIC disabled"),
+        Err(error) => println!("Using legacy PIC: {}", error),
+    }
+    
     // Initialize drivers
     drivers::timer::SYSTEM_TIMER.lock().init()
         .expect("Timer initialization failed");
@@ -68,8 +86,10 @@ pub extern "C" fn _start() -> ! {
     println!("Boxed value: {}", x);
     
     // Test process creation
-    let pid1 = process::create_process(alloc::string::String::from("test_process_1"));
-    let pid2 = process::create_process(alloc::string::String::from("test_process_2"));
+    let pid1 = process::create_process(alloc::string::String::from("test_process_1"))
+        .expect("Process creation failed");
+    let pid2 = process::create_process(alloc::string::String::from("test_process_2"))
+        .expect("Process creation failed");
     println!("Created processes with PIDs: {}, {}", pid1, pid2);
     
     #[cfg(test)]
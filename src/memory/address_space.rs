@@ -0,0 +1,516 @@
+//! Per-process address spaces for KewveOS
+//!
+//! Every process owns a level 4 page table. The PML4 entries that cover the
+//! user range (`USER_SPACE_START..USER_SPACE_END`) are private to the process.
+//! All other entries are copied from the kernel's page table, so the kernel
+//! image, stacks, heap and physical memory mapping are shared by every address
+//! space and stay valid across a CR3 switch.
+
+use super::paging::{self, table_at, COPY_ON_WRITE, OWNED_FRAME, SHARED_FRAME};
+use super::vma::{AreaSet, VirtualMemoryArea};
+use super::{frame_refs, phys_to_virt, try_with_frame_allocator, with_frame_allocator, BootInfoFrameAllocator, MemoryError, MemoryFlags, PAGE_SIZE};
+use alloc::sync::Arc;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts::without_interrupts, tlb},
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, MapperFlush, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        page_table::PageTableEntry, PageTableFlags, PageTableIndex, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// First address of the per-process user range
+pub const USER_SPACE_START: u64 = 0x0000_6000_0000_0000;
+/// End of the per-process user range (exclusive)
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// PML4 entries that are private to each address space
+const USER_PML4_ENTRIES: Range<usize> = 192..256;
+
+/// Flags for intermediate tables in the user range; leaf entries carry the real permissions
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Physical address of the kernel's level 4 table
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+/// Record the boot page table as the kernel address space
+pub(super) fn init() {
+    // Make kernel writes honour read-only pages so copy-on-write cannot be bypassed
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
+    let (frame, _) = Cr3::read();
+    KERNEL_PML4.store(frame.start_address().as_u64(), Ordering::Relaxed);
+}
+
+/// Level 4 table of the kernel address space
+pub fn kernel_pml4() -> Option<PhysFrame> {
+    match KERNEL_PML4.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// Address space loaded in CR3; `None` is the kernel address space
+static CURRENT: Mutex<Option<Arc<AddressSpace>>> = Mutex::new(None);
+
+/// Load `space` into CR3, or the kernel address space for `None`
+pub fn switch_to(space: Option<&Arc<AddressSpace>>) {
+    without_interrupts(|| {
+        let mut current = CURRENT.lock();
+        match space {
+            Some(space) => load_cr3(space.pml4),
+            None => {
+                if let Some(frame) = kernel_pml4() {
+                    load_cr3(frame);
+                }
+            }
+        }
+        *current = space.cloned();
+    })
+}
+
+/// The address space currently loaded in CR3, if it is not the kernel's
+pub fn current() -> Option<Arc<AddressSpace>> {
+    without_interrupts(|| CURRENT.lock().clone())
+}
+
+/// Whether `addr` lies in the per-process user range
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr.as_u64())
+}
+
+/// An isolated set of page tables owned by one process
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    tables: Mutex<OffsetPageTable<'static>>,
+    areas: Mutex<AreaSet>,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user range and the kernel mappings shared
+    pub fn new() -> Result<Self, MemoryError> {
+        let kernel = kernel_pml4().ok_or(MemoryError::NotInitialized)?;
+        let pml4 = with_frame_allocator(|frames| frames.allocate_frame().ok_or(MemoryError::OutOfMemory))?;
+        paging::zero_frame(pml4);
+
+        let table = unsafe { table_at(pml4) };
+        let kernel_table = unsafe { table_at(kernel) };
+        for index in (0..512).filter(|index| !USER_PML4_ENTRIES.contains(index)) {
+            table[index] = kernel_table[index].clone();
+        }
+
+        let tables = unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) };
+        Ok(Self {
+            pml4,
+            tables: Mutex::new(tables),
+            areas: Mutex::new(AreaSet::new()),
+        })
+    }
+
+    /// Physical frame of this address space's level 4 table
+    pub fn pml4_frame(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Whether this address space is loaded in CR3
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    /// Map `count` zero-filled pages at `start`, backed by frames owned by this address space
+    pub fn map_anonymous(&self, start: VirtAddr, count: usize, flags: MemoryFlags) -> Result<(), MemoryError> {
+        let page = user_pages(start, count)?;
+        let active = self.is_active();
+
+        without_interrupts(|| {
+            let mut tables = self.tables.lock();
+            with_frame_allocator(|frames| {
+                for i in 0..count as u64 {
+                    let mapped = frames.allocate_frame().ok_or(MemoryError::OutOfMemory).and_then(|frame| {
+                        paging::zero_frame(frame);
+                        map_owned_frame(&mut tables, frames, page + i, frame, flags, active)
+                    });
+                    if let Err(error) = mapped {
+                        unmap_pages(&mut tables, frames, page, i as usize, active, true)?;
+                        return Err(error);
+                    }
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Map an already populated frame at `page`; the address space takes ownership of it
+    ///
+    /// If the page was mapped in the meantime the frame is freed instead.
+    pub fn map_owned(&self, page: Page, frame: PhysFrame, flags: MemoryFlags) -> Result<(), MemoryError> {
+        user_pages(page.start_address(), 1)?;
+        let active = self.is_active();
+        without_interrupts(|| {
+            let mut tables = self.tables.lock();
+            with_frame_allocator(|frames| match map_owned_frame(&mut tables, frames, page, frame, flags, active) {
+                Err(MemoryError::PageAlreadyMapped(_)) => Ok(()),
+                result => result,
+            })
+        })
+    }
+
+    /// Map shared `frames` from `start`, taking a reference on each
+    ///
+    /// The caller has already reserved the area; see `shared::map_object`.
+    pub(super) fn map_shared_frames(
+        &self,
+        start: VirtAddr,
+        frames: &[PhysFrame],
+        flags: MemoryFlags,
+    ) -> Result<(), MemoryError> {
+        let page = user_pages(start, frames.len())?;
+        let flags = flags.validate()?.page_table_flags() | OWNED_FRAME | SHARED_FRAME;
+        let active = self.is_active();
+
+        without_interrupts(|| {
+            let mut tables = self.tables.lock();
+            with_frame_allocator(|allocator| {
+                for (i, &frame) in frames.iter().enumerate() {
+                    if let Err(error) = frame_refs::acquire(frame) {
+                        unmap_pages(&mut tables, allocator, page, i, active, true)?;
+                        return Err(error);
+                    }
+                    let target = page + i as u64;
+                    let mapped = unsafe { tables.map_to_with_table_flags(target, frame, flags, USER_TABLE_FLAGS, allocator) };
+                    match mapped {
+                        Ok(flush) => flush_if(flush, active),
+                        Err(error) => {
+                            frame_refs::release(frame);
+                            unmap_pages(&mut tables, allocator, page, i, active, true)?;
+                            return Err(match error {
+                                MapToError::FrameAllocationFailed => MemoryError::OutOfMemory,
+                                MapToError::PageAlreadyMapped(_) => MemoryError::PageAlreadyMapped(target.start_address()),
+                                MapToError::ParentEntryHugePage => MemoryError::MappingFailed,
+                            });
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Reserve a lazily populated area in the user range
+    pub fn add_area(&self, area: VirtualMemoryArea) -> Result<(), MemoryError> {
+        let pages = ((area.end - area.start) as usize).div_ceil(PAGE_SIZE);
+        user_pages(area.start, pages)?;
+        without_interrupts(|| self.areas.lock().insert(area))
+    }
+
+    /// Remove the area starting at `start`, releasing every page populated in it
+    pub fn remove_area(&self, start: VirtAddr) -> Result<(), MemoryError> {
+        let area = without_interrupts(|| self.areas.lock().remove(start))
+            .ok_or(MemoryError::InvalidVirtualAddress(start))?;
+        let page = Page::containing_address(area.start);
+        let count = ((area.end - area.start) as usize).div_ceil(PAGE_SIZE);
+        let active = self.is_active();
+        without_interrupts(|| {
+            let mut tables = self.tables.lock();
+            with_frame_allocator(|frames| unmap_pages(&mut tables, frames, page, count, active, false))
+        })
+    }
+
+    /// The area containing `addr`, if any
+    pub fn find_area(&self, addr: VirtAddr) -> Option<VirtualMemoryArea> {
+        without_interrupts(|| self.areas.lock().find(addr).cloned())
+    }
+
+    /// Unmap `count` pages at `start`, freeing any frames this address space owns
+    pub fn unmap(&self, start: VirtAddr, count: usize) -> Result<(), MemoryError> {
+        let page = user_pages(start, count)?;
+        let active = self.is_active();
+        without_interrupts(|| {
+            let mut tables = self.tables.lock();
+            with_frame_allocator(|frames| unmap_pages(&mut tables, frames, page, count, active, true))
+        })
+    }
+
+    /// Duplicate this address space for a child process
+    ///
+    /// No memory is copied: every owned page is shared with the child and
+    /// mapped read-only in both, and the first write to it by either side
+    /// copies the frame. Shared memory pages stay shared and writable.
+    pub fn duplicate(&self) -> Result<AddressSpace, MemoryError> {
+        let mut child = AddressSpace::new()?;
+        *child.areas.get_mut() = without_interrupts(|| self.areas.lock().clone());
+
+        let active = self.is_active();
+        without_interrupts(|| {
+            let mut parent = self.tables.lock();
+            let child_tables = child.tables.get_mut();
+            with_frame_allocator(|frames| share_user_pages(parent.level_4_table(), child_tables, frames))
+        })?;
+        if active {
+            // Writable parent pages were just made read-only
+            tlb::flush_all();
+        }
+        Ok(child)
+    }
+
+    /// Give the faulting page at `addr` a private, writable frame
+    ///
+    /// Returns an error if the page is not a copy-on-write page. Runs in the
+    /// page fault handler, which may have interrupted a holder of the page
+    /// table or frame allocator lock, so it fails instead of waiting for them.
+    pub fn resolve_copy_on_write(&self, addr: VirtAddr) -> Result<(), MemoryError> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let active = self.is_active();
+        without_interrupts(|| {
+            let mut tables = self.tables.try_lock().ok_or(MemoryError::MappingFailed)?;
+            let (frame, flags) = match tables.translate(addr) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+                _ => return Err(MemoryError::InvalidVirtualAddress(addr)),
+            };
+            if !flags.contains(COPY_ON_WRITE) {
+                return Err(MemoryError::InvalidVirtualAddress(addr));
+            }
+            let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+            if frame_refs::count(frame) == 1 {
+                // Every other owner is gone, so the frame can simply be reused
+                let flush = unsafe { tables.update_flags(page, writable) }.map_err(|_| MemoryError::MappingFailed)?;
+                flush_if(flush, active);
+                return Ok(());
+            }
+
+            try_with_frame_allocator(|frames| {
+                let copy = frames.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                        phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                        PAGE_SIZE,
+                    );
+                }
+
+                let (_, flush) = tables.unmap(page).map_err(|_| MemoryError::MappingFailed)?;
+                flush_if(flush, active);
+                let flush = unsafe { tables.map_to_with_table_flags(page, copy, writable, USER_TABLE_FLAGS, frames) }
+                    .map_err(|_| MemoryError::MappingFailed)?;
+                flush_if(flush, active);
+
+                if frame_refs::release(frame) {
+                    unsafe { frames.deallocate_frame(frame) };
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Translate a virtual address in this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        without_interrupts(|| self.tables.lock().translate_addr(addr))
+    }
+
+    /// Page table flags of the page mapped at `addr`, if any
+    pub fn page_flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        without_interrupts(|| match self.tables.lock().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Never tear down the tables we are running on; CURRENT cannot be
+        // locked here because the last reference may be dropped by `switch_to`
+        if let Some(kernel) = kernel_pml4().filter(|_| self.is_active()) {
+            load_cr3(kernel);
+        }
+
+        let table = self.tables.get_mut().level_4_table();
+        let _ = with_frame_allocator(|frames| {
+            for index in USER_PML4_ENTRIES {
+                free_entry(&mut table[index], 3, frames);
+            }
+            unsafe { frames.deallocate_frame(self.pml4) };
+            Ok(())
+        });
+    }
+}
+
+impl fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AddressSpace")
+            .field("pml4", &self.pml4.start_address())
+            .finish()
+    }
+}
+
+/// Validate that `[start, start + count pages)` is page aligned and inside the user range
+fn user_pages(start: VirtAddr, count: usize) -> Result<Page, MemoryError> {
+    let page = Page::from_start_address(start).map_err(|_| MemoryError::InvalidVirtualAddress(start))?;
+    let end = start.as_u64() + count as u64 * PAGE_SIZE as u64;
+    if !is_user_address(start) || end > USER_SPACE_END {
+        return Err(MemoryError::InvalidVirtualAddress(start));
+    }
+    Ok(page)
+}
+
+fn map_owned_frame(
+    tables: &mut OffsetPageTable<'static>,
+    frames: &mut BootInfoFrameAllocator,
+    page: Page,
+    frame: PhysFrame,
+    flags: MemoryFlags,
+    active: bool,
+) -> Result<(), MemoryError> {
+    if let Err(error) = flags.validate() {
+        unsafe { frames.deallocate_frame(frame) };
+        return Err(error);
+    }
+    let flags = flags.page_table_flags() | OWNED_FRAME;
+    match unsafe { tables.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frames) } {
+        Ok(flush) => {
+            flush_if(flush, active);
+            Ok(())
+        }
+        Err(error) => {
+            unsafe { frames.deallocate_frame(frame) };
+            Err(match error {
+                MapToError::FrameAllocationFailed => MemoryError::OutOfMemory,
+                MapToError::PageAlreadyMapped(_) => MemoryError::PageAlreadyMapped(page.start_address()),
+                MapToError::ParentEntryHugePage => MemoryError::MappingFailed,
+            })
+        }
+    }
+}
+
+/// Unmap `count` pages at `page`; unmapped pages are an error only if `require_mapped`
+fn unmap_pages(
+    tables: &mut OffsetPageTable<'static>,
+    frames: &mut BootInfoFrameAllocator,
+    page: Page,
+    count: usize,
+    active: bool,
+    require_mapped: bool,
+) -> Result<(), MemoryError> {
+    for i in 0..count as u64 {
+        let owned = match tables.translate((page + i).start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(OWNED_FRAME),
+            _ if !require_mapped => continue,
+            _ => return Err(MemoryError::InvalidVirtualAddress((page + i).start_address())),
+        };
+        let (frame, flush) = tables.unmap(page + i).map_err(|_| MemoryError::MappingFailed)?;
+        flush_if(flush, active);
+        if owned && frame_refs::release(frame) {
+            unsafe { frames.deallocate_frame(frame) };
+        }
+    }
+    Ok(())
+}
+
+/// Free everything reachable from `entry`, which points to a table of `level`
+///
+/// Level 0 means `entry` is a leaf mapping a 4 KiB frame.
+fn free_entry(entry: &mut PageTableEntry, level: u8, frames: &mut BootInfoFrameAllocator) {
+    if entry.is_unused() {
+        return;
+    }
+    let flags = entry.flags();
+    let frame = PhysFrame::containing_address(entry.addr());
+
+    if level == 0 {
+        if flags.contains(OWNED_FRAME) && frame_refs::release(frame) {
+            unsafe { frames.deallocate_frame(frame) };
+        }
+    } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+        let table = unsafe { table_at(frame) };
+        for child in table.iter_mut() {
+            free_entry(child, level - 1, frames);
+        }
+        unsafe { frames.deallocate_frame(frame) };
+    }
+    entry.set_unused();
+}
+
+/// Map every user page of `parent` into `child`, sharing owned frames copy-on-write
+fn share_user_pages(
+    parent: &mut PageTable,
+    child: &mut OffsetPageTable<'static>,
+    frames: &mut BootInfoFrameAllocator,
+) -> Result<(), MemoryError> {
+    for p4 in USER_PML4_ENTRIES {
+        let Some(level_3) = next_table(&parent[p4]) else { continue };
+        for p3 in 0..512 {
+            let Some(level_2) = next_table(&level_3[p3]) else { continue };
+            for p2 in 0..512 {
+                let Some(level_1) = next_table(&level_2[p2]) else { continue };
+                for p1 in 0..512 {
+                    let entry = &mut level_1[p1];
+                    if entry.is_unused() {
+                        continue;
+                    }
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    let mut flags = entry.flags();
+                    if flags.contains(OWNED_FRAME) {
+                        if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED_FRAME) {
+                            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                            entry.set_flags(flags);
+                        }
+                        frame_refs::acquire(frame)?;
+                    }
+
+                    let page = Page::from_page_table_indices(
+                        PageTableIndex::new(p4 as u16),
+                        PageTableIndex::new(p3 as u16),
+                        PageTableIndex::new(p2 as u16),
+                        PageTableIndex::new(p1 as u16),
+                    );
+                    let mapped = unsafe { child.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frames) };
+                    match mapped {
+                        // The child is not active, so there is nothing to flush
+                        Ok(flush) => flush.ignore(),
+                        Err(_) => {
+                            if flags.contains(OWNED_FRAME) {
+                                frame_refs::release(frame);
+                            }
+                            return Err(MemoryError::MappingFailed);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The table an entry points to, unless it is empty or maps a huge page
+fn next_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    Some(unsafe { table_at(PhysFrame::containing_address(entry.addr())) })
+}
+
+fn flush_if(flush: MapperFlush<Size4KiB>, active: bool) {
+    // Inactive address spaces have no TLB entries; they are flushed on the next CR3 load
+    if active {
+        flush.flush();
+    } else {
+        flush.ignore();
+    }
+}
+
+fn load_cr3(frame: PhysFrame) {
+    let (current, flags) = Cr3::read();
+    if current != frame {
+        // The kernel mappings are shared by every address space, so execution continues safely
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
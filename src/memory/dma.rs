@@ -0,0 +1,95 @@
+//! DMA buffers for device drivers
+//!
+//! A `DmaBuffer` is a physically contiguous block of frames taken from a
+//! memory zone the device can address. Drivers hand the physical address to
+//! the device and access the contents through the kernel's physical memory
+//! mapping. x86 keeps DMA coherent with the CPU caches, so no extra cache
+//! maintenance is needed.
+
+use super::frame_allocator::{MemoryZone, FRAME_SIZE};
+use super::{phys_to_virt, with_frame_allocator, MemoryError};
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+/// A physically contiguous, zeroed buffer that returns its frames on drop
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    size: usize,
+    frames: usize,
+    zone: MemoryZone,
+}
+
+impl DmaBuffer {
+    /// Allocate a page-aligned buffer of at least `size` bytes in `zone`
+    pub fn new(size: usize, zone: MemoryZone) -> Result<Self, MemoryError> {
+        Self::with_alignment(size, FRAME_SIZE as usize, zone)
+    }
+
+    /// Allocate a buffer whose physical address is aligned to `align` bytes
+    ///
+    /// Useful for devices that require buffers not to cross a 64 KiB boundary,
+    /// such as the ISA DMA controller.
+    pub fn with_alignment(size: usize, align: usize, zone: MemoryZone) -> Result<Self, MemoryError> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(MemoryError::FrameAllocationFailed);
+        }
+        let frames = size.div_ceil(FRAME_SIZE as usize);
+        let align_frames = (align / FRAME_SIZE as usize).max(1);
+        let first = with_frame_allocator(|frame_allocator| {
+            frame_allocator
+                .allocate_in_zone(frames, align_frames, zone)
+                .ok_or(MemoryError::OutOfMemory)
+        })?;
+
+        let buffer = DmaBuffer {
+            phys: first.start_address(),
+            size,
+            frames,
+            zone,
+        };
+        unsafe { core::ptr::write_bytes(buffer.virt_addr().as_mut_ptr::<u8>(), 0, frames * FRAME_SIZE as usize) };
+        Ok(buffer)
+    }
+
+    /// Physical address to program into the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Kernel virtual address of the buffer
+    pub fn virt_addr(&self) -> VirtAddr {
+        phys_to_virt(self.phys)
+    }
+
+    /// Requested size of the buffer in bytes
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the buffer is empty; never true for an allocated buffer
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Zone the buffer was requested from
+    pub fn zone(&self) -> MemoryZone {
+        self.zone
+    }
+
+    /// Buffer contents
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt_addr().as_ptr(), self.size) }
+    }
+
+    /// Mutable buffer contents
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let first = PhysFrame::containing_address(self.phys);
+        let _ = with_frame_allocator(|frame_allocator| frame_allocator.deallocate_contiguous(first, self.frames));
+    }
+}
@@ -0,0 +1,88 @@
+//! Page fault resolution for KewveOS
+//!
+//! Faults on not-present pages inside a registered virtual memory area are
+//! resolved by allocating a frame, filling it with the area's initial
+//! contents and mapping it. Write faults on copy-on-write pages are resolved
+//! by giving the writer a private copy. Everything else is reported back to
+//! the interrupt handler as an invalid access.
+
+use super::vma::{VirtualMemoryArea, KERNEL_AREAS};
+use super::{address_space, paging, phys_to_virt, try_with_frame_allocator, try_with_memory, MemoryError, PAGE_SIZE};
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameAllocator, FrameDeallocator, Page, PhysFrame},
+    },
+    VirtAddr,
+};
+
+/// Try to resolve a page fault at `addr`
+///
+/// Returns `Ok(())` if the faulting access can be retried.
+pub fn handle_page_fault(addr: VirtAddr, error: PageFaultErrorCode) -> Result<(), MemoryError> {
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && address_space::is_user_address(addr) {
+            let space = address_space::current().ok_or(MemoryError::InvalidVirtualAddress(addr))?;
+            return space.resolve_copy_on_write(addr);
+        }
+        return Err(MemoryError::InvalidVirtualAddress(addr));
+    }
+
+    let page = Page::containing_address(addr);
+    if address_space::is_user_address(addr) {
+        let space = address_space::current().ok_or(MemoryError::InvalidVirtualAddress(addr))?;
+        let area = space.find_area(addr).ok_or(MemoryError::InvalidVirtualAddress(addr))?;
+        if !area.permits(error) {
+            return Err(MemoryError::InvalidVirtualAddress(addr));
+        }
+        let frame = populate_frame(&area, page)?;
+        space.map_owned(page, frame, area.flags)
+    } else {
+        let area = KERNEL_AREAS
+            .try_lock()
+            .and_then(|areas| areas.find(addr).cloned())
+            .ok_or(MemoryError::InvalidVirtualAddress(addr))?;
+        if !area.permits(error) {
+            return Err(MemoryError::InvalidVirtualAddress(addr));
+        }
+        let frame = populate_frame(&area, page)?;
+        // The fault may have interrupted a holder of the kernel mapper lock
+        let mut locked = false;
+        let result = try_with_memory(|mapper, frame_allocator| {
+            locked = true;
+            paging::map_range(mapper, frame_allocator, page, frame, 1, area.flags)
+                .inspect_err(|_| unsafe { frame_allocator.deallocate_frame(frame) })
+        });
+        if !locked {
+            free_frame(frame);
+        }
+        result
+    }
+}
+
+/// Allocate a frame for `page` and fill it with the area's initial contents
+///
+/// The fault may have interrupted a holder of the frame allocator lock, so
+/// this fails rather than waiting for it, and never reclaims memory.
+fn populate_frame(area: &VirtualMemoryArea, page: Page) -> Result<PhysFrame, MemoryError> {
+    let frame = try_with_frame_allocator(|frames| frames.allocate_frame().ok_or(MemoryError::OutOfMemory))?;
+    let contents = unsafe {
+        core::slice::from_raw_parts_mut(phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), PAGE_SIZE)
+    };
+    if let Err(error) = area.populate(page.start_address(), contents) {
+        free_frame(frame);
+        return Err(error);
+    }
+    Ok(frame)
+}
+
+/// Free a frame that was never mapped
+///
+/// The frame leaks if the frame allocator lock is held, since waiting for it
+/// here could deadlock.
+fn free_frame(frame: PhysFrame) {
+    let _ = try_with_frame_allocator(|frames| {
+        unsafe { frames.deallocate_frame(frame) };
+        Ok(())
+    });
+}
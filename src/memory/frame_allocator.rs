@@ -0,0 +1,344 @@
+//! Physical frame allocator for KewveOS
+//!
+//! Tracks every 4 KiB physical frame in a bitmap that is carved out of the
+//! first usable region large enough to hold it. A set bit means the frame is
+//! in use (or not RAM at all), a clear bit means it is free. The bitmap is
+//! reached through the bootloader's physical memory mapping, so no heap is
+//! needed to bring the allocator up.
+//!
+//! Physical memory is split into zones by the highest address that devices
+//! with limited DMA reach can use. Allocations are served from the highest
+//! zone the caller accepts, so scarce low memory stays available for the
+//! devices that need it.
+
+use super::MemoryError;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Size of a physical frame in bytes
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Number of frames tracked by one bitmap word
+const BITS_PER_WORD: usize = 64;
+
+/// Physical memory zones for devices with limited DMA addressing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryZone {
+    /// Below 16 MiB, for legacy ISA DMA
+    Dma16,
+    /// Below 4 GiB, for devices with 32-bit DMA addresses
+    Dma32,
+    /// Anywhere in physical memory
+    Normal,
+}
+
+impl MemoryZone {
+    /// Frame numbers belonging to this zone alone
+    pub const fn frames(self) -> Range<usize> {
+        const DMA16_END: usize = (16 * 1024 * 1024 / FRAME_SIZE) as usize;
+        const DMA32_END: usize = (4 * 1024 * 1024 * 1024 / FRAME_SIZE) as usize;
+        match self {
+            MemoryZone::Dma16 => 0..DMA16_END,
+            MemoryZone::Dma32 => DMA16_END..DMA32_END,
+            MemoryZone::Normal => DMA32_END..usize::MAX,
+        }
+    }
+
+    /// Zones an allocation for this zone may be served from, in order of preference
+    fn fallbacks(self) -> &'static [MemoryZone] {
+        match self {
+            MemoryZone::Dma16 => &[MemoryZone::Dma16],
+            MemoryZone::Dma32 => &[MemoryZone::Dma32, MemoryZone::Dma16],
+            MemoryZone::Normal => &[MemoryZone::Normal, MemoryZone::Dma32, MemoryZone::Dma16],
+        }
+    }
+}
+
+/// Physical memory broken down by memory map region type, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegionStats {
+    /// RAM the kernel may allocate from
+    pub usable: u64,
+    /// Kernel image and boot stack
+    pub kernel: u64,
+    /// Page tables set up by the bootloader
+    pub page_tables: u64,
+    /// Bootloader code, boot info and other in-use memory
+    pub bootloader: u64,
+    /// Firmware reserved, ACPI and bad memory
+    pub reserved: u64,
+}
+
+impl RegionStats {
+    fn from_memory_map(memory_map: &MemoryMap) -> Self {
+        let mut stats = Self::default();
+        for region in memory_map.iter() {
+            let bytes = (region.range.end_frame_number - region.range.start_frame_number) * FRAME_SIZE;
+            match region.region_type {
+                MemoryRegionType::Usable => stats.usable += bytes,
+                MemoryRegionType::Kernel | MemoryRegionType::KernelStack => stats.kernel += bytes,
+                MemoryRegionType::PageTable => stats.page_tables += bytes,
+                MemoryRegionType::Bootloader
+                | MemoryRegionType::BootInfo
+                | MemoryRegionType::Package
+                | MemoryRegionType::FrameZero
+                | MemoryRegionType::InUse => stats.bootloader += bytes,
+                MemoryRegionType::Empty => {}
+                _ => stats.reserved += bytes,
+            }
+        }
+        stats
+    }
+
+    /// Total physical memory described by the memory map
+    pub fn total(&self) -> u64 {
+        self.usable + self.kernel + self.page_tables + self.bootloader + self.reserved
+    }
+}
+
+/// Bitmap-based physical frame allocator built from the bootloader memory map
+pub struct BootInfoFrameAllocator {
+    /// One bit per physical frame, starting at physical address 0
+    bitmap: &'static mut [u64],
+    /// Number of frames covered by the bitmap
+    frame_count: usize,
+    /// Number of frames reported as usable by the memory map
+    usable_frames: usize,
+    /// Number of frames currently free
+    free_frames: usize,
+    /// Lowest number of free frames ever observed
+    min_free_frames: usize,
+    /// Memory map breakdown captured at initialization
+    regions: RegionStats,
+    /// Word index where the next single-frame search starts
+    next_word: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a new frame allocator from bootloader memory map
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames marked as `USABLE` are really unused,
+    /// and that all physical memory is mapped at `physical_memory_offset`.
+    pub unsafe fn init(
+        memory_map: &'static MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> Result<Self, MemoryError> {
+        let usable = || memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let frame_count = usable()
+            .map(|r| r.range.end_frame_number as usize)
+            .max()
+            .ok_or(MemoryError::OutOfMemory)?;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = ((words * 8) as u64).div_ceil(FRAME_SIZE);
+
+        // Place the bitmap at the start of the first usable region that fits it
+        let bitmap_start = usable()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .map(|r| r.range.start_frame_number)
+            .ok_or(MemoryError::OutOfMemory)?;
+
+        let bitmap_virt = physical_memory_offset + bitmap_start * FRAME_SIZE;
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_virt.as_mut_ptr::<u64>(), words);
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BootInfoFrameAllocator {
+            bitmap,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            min_free_frames: 0,
+            regions: RegionStats::from_memory_map(memory_map),
+            next_word: 0,
+        };
+
+        for region in usable() {
+            let start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            allocator.set_range(start, end - start, false);
+            allocator.usable_frames += end - start;
+            allocator.free_frames += end - start;
+        }
+
+        // The bitmap itself and frame zero are never handed out
+        allocator.reserve_range(bitmap_start as usize, bitmap_frames as usize);
+        allocator.reserve_range(0, 1);
+        allocator.min_free_frames = allocator.free_frames;
+
+        Ok(allocator)
+    }
+
+    /// Total number of frames reported as usable by the memory map
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Number of frames currently available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Highest number of usable frames ever allocated at once
+    pub fn peak_used_frames(&self) -> usize {
+        self.usable_frames - self.min_free_frames
+    }
+
+    /// Memory map breakdown by region type
+    pub fn regions(&self) -> RegionStats {
+        self.regions
+    }
+
+    /// Number of free frames in `zone`
+    pub fn free_frames_in(&self, zone: MemoryZone) -> usize {
+        let frames = zone.frames();
+        (frames.start..frames.end.min(self.frame_count))
+            .filter(|&frame| !self.is_used(frame))
+            .count()
+    }
+
+    /// Allocate `count` physically contiguous frames
+    ///
+    /// The first frame is aligned to `align` frames, which must be a power of two.
+    /// Returns the first frame of the run.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 1 && align == 1 {
+            return self.allocate_single();
+        }
+        self.allocate_in_zone(count, align, MemoryZone::Normal)
+    }
+
+    /// Allocate `count` contiguous frames that all lie within `zone` or a lower zone
+    pub fn allocate_in_zone(&mut self, count: usize, align: usize, zone: MemoryZone) -> Option<PhysFrame> {
+        zone.fallbacks().iter().find_map(|zone| {
+            let frames = zone.frames();
+            self.allocate_contiguous_in(count, align, frames.start, frames.end)
+        })
+    }
+
+    /// Allocate `count` contiguous frames whose frame numbers lie in `[lowest, highest)`
+    pub fn allocate_contiguous_in(
+        &mut self,
+        count: usize,
+        align: usize,
+        lowest: usize,
+        highest: usize,
+    ) -> Option<PhysFrame> {
+        debug_assert!(align.is_power_of_two());
+        if count == 0 {
+            return None;
+        }
+        if count == 1 && align == 1 && lowest == 0 && highest >= self.frame_count {
+            return self.allocate_single();
+        }
+
+        let highest = highest.min(self.frame_count);
+        let mut start = align_up(lowest, align);
+        while start + count <= highest {
+            match (start..start + count).rev().find(|&frame| self.is_used(frame)) {
+                Some(used) => start = align_up(used + 1, align),
+                None => {
+                    self.set_range(start, count, true);
+                    self.take_frames(count);
+                    return Some(frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Return `count` contiguous frames starting at `start` to the allocator
+    pub fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) -> Result<(), MemoryError> {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        if first == 0 || first + count > self.frame_count {
+            return Err(MemoryError::InvalidPhysicalAddress(start.start_address()));
+        }
+        if let Some(free) = (first..first + count).find(|&frame| !self.is_used(frame)) {
+            // Double free: refuse rather than corrupt the free count
+            return Err(MemoryError::InvalidPhysicalAddress(frame_at(free).start_address()));
+        }
+
+        self.set_range(first, count, false);
+        self.free_frames += count;
+        self.next_word = self.next_word.min(first / BITS_PER_WORD);
+        Ok(())
+    }
+
+    /// Mark a range of frames as in use without going through allocation
+    fn reserve_range(&mut self, start: usize, count: usize) {
+        let newly_used = (start..start + count).filter(|&frame| !self.is_used(frame)).count();
+        self.set_range(start, count, true);
+        self.take_frames(newly_used);
+    }
+
+    fn take_frames(&mut self, count: usize) {
+        self.free_frames -= count;
+        self.min_free_frames = self.min_free_frames.min(self.free_frames);
+    }
+
+    /// Fast path for single frames: skip fully used words from the search hint
+    ///
+    /// Frames below 16 MiB are only handed out once everything above is used.
+    fn allocate_single(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        let dma16_words = (MemoryZone::Dma16.frames().end / BITS_PER_WORD).min(words);
+        let hint = self.next_word.max(dma16_words);
+        let search = (hint..words).chain(dma16_words..hint).chain(0..dma16_words);
+        for word_index in search {
+            let word = self.bitmap[word_index];
+            if word == u64::MAX {
+                continue;
+            }
+            let frame = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if frame >= self.frame_count {
+                continue;
+            }
+            self.bitmap[word_index] |= 1 << (frame % BITS_PER_WORD);
+            self.take_frames(1);
+            self.next_word = word_index;
+            return Some(frame_at(frame));
+        }
+        None
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_range(&mut self, start: usize, count: usize, used: bool) {
+        for frame in start..start + count {
+            let bit = 1 << (frame % BITS_PER_WORD);
+            if used {
+                self.bitmap[frame / BITS_PER_WORD] |= bit;
+            } else {
+                self.bitmap[frame / BITS_PER_WORD] &= !bit;
+            }
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_single()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 1)
+            .expect("Attempted to free an unallocated physical frame");
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn frame_at(frame_number: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(frame_number as u64 * FRAME_SIZE))
+}
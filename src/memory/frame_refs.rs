@@ -0,0 +1,134 @@
+//! Reference counts for physical frames shared between mappings
+//!
+//! Frames mapped by a single owner are not tracked. Once a frame gains a
+//! second owner (for example through copy-on-write duplication) it gets an
+//! entry here, and it is only returned to the frame allocator when the last
+//! owner releases it.
+//!
+//! The counts live in a fixed-size hash table rather than on the heap,
+//! because they are updated with the frame allocator and page tables locked,
+//! where growing the heap is impossible.
+
+use super::MemoryError;
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, structures::paging::PhysFrame};
+
+/// Slots in the table; a power of two
+const TABLE_SIZE: usize = 16384;
+/// Most frames that can be shared at once, keeping probe sequences short
+pub const MAX_SHARED_FRAMES: usize = TABLE_SIZE / 4 * 3;
+
+/// Owner count of one shared frame; `owners == 0` marks a free slot
+#[derive(Clone, Copy)]
+struct Entry {
+    frame: u64,
+    owners: usize,
+}
+
+const FREE: Entry = Entry { frame: 0, owners: 0 };
+
+/// Open addressing table with linear probing, keyed by physical address
+struct RefTable {
+    entries: [Entry; TABLE_SIZE],
+    len: usize,
+}
+
+impl RefTable {
+    const fn new() -> Self {
+        Self {
+            entries: [FREE; TABLE_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Slot a frame's probe sequence starts at
+    fn home(frame: u64) -> usize {
+        ((frame >> 12).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 50) as usize & (TABLE_SIZE - 1)
+    }
+
+    /// Slot holding `frame`, or the free slot where it would go
+    fn slot(&self, frame: u64) -> usize {
+        let mut slot = Self::home(frame);
+        while self.entries[slot].owners != 0 && self.entries[slot].frame != frame {
+            slot = (slot + 1) & (TABLE_SIZE - 1);
+        }
+        slot
+    }
+
+    /// Free `slot`, moving later entries of the same probe run back into the gap
+    fn remove(&mut self, mut hole: usize) {
+        let mut next = (hole + 1) & (TABLE_SIZE - 1);
+        while self.entries[next].owners != 0 {
+            let home = Self::home(self.entries[next].frame);
+            // An entry may only move back if that keeps it at or after its home slot
+            if next.wrapping_sub(home) & (TABLE_SIZE - 1) >= next.wrapping_sub(hole) & (TABLE_SIZE - 1) {
+                self.entries[hole] = self.entries[next];
+                hole = next;
+            }
+            next = (next + 1) & (TABLE_SIZE - 1);
+        }
+        self.entries[hole] = FREE;
+        self.len -= 1;
+    }
+}
+
+/// Owner count per shared frame
+static SHARED_FRAMES: Mutex<RefTable> = Mutex::new(RefTable::new());
+
+/// Number of owners of `frame`
+pub fn count(frame: PhysFrame) -> usize {
+    without_interrupts(|| {
+        let shared = SHARED_FRAMES.lock();
+        let entry = shared.entries[shared.slot(frame.start_address().as_u64())];
+        if entry.owners == 0 {
+            1
+        } else {
+            entry.owners
+        }
+    })
+}
+
+/// Whether the reference counts are locked
+pub(super) fn is_locked() -> bool {
+    SHARED_FRAMES.is_locked()
+}
+
+/// Record an additional owner of `frame`
+///
+/// Fails with `OutOfMemory` if `MAX_SHARED_FRAMES` frames are shared already.
+pub fn acquire(frame: PhysFrame) -> Result<(), MemoryError> {
+    without_interrupts(|| {
+        let mut shared = SHARED_FRAMES.lock();
+        let key = frame.start_address().as_u64();
+        let slot = shared.slot(key);
+        if shared.entries[slot].owners == 0 {
+            if shared.len == MAX_SHARED_FRAMES {
+                return Err(MemoryError::OutOfMemory);
+            }
+            shared.entries[slot] = Entry { frame: key, owners: 1 };
+            shared.len += 1;
+        }
+        shared.entries[slot].owners += 1;
+        Ok(())
+    })
+}
+
+/// Drop one owner of `frame`
+///
+/// Returns `true` if the caller was the last owner and must free the frame.
+pub fn release(frame: PhysFrame) -> bool {
+    without_interrupts(|| {
+        let mut shared = SHARED_FRAMES.lock();
+        let slot = shared.slot(frame.start_address().as_u64());
+        match shared.entries[slot].owners {
+            0 => true,
+            _ => {
+                shared.entries[slot].owners -= 1;
+                if shared.entries[slot].owners == 1 {
+                    shared.remove(slot);
+                }
+                false
+            }
+        }
+    })
+}
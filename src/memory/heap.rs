@@ -0,0 +1,189 @@
+//! Kernel heap allocator for KewveOS
+//!
+//! Wraps `linked_list_allocator::Heap` so that the memory subsystem can
+//! account for heap usage, including the high-water mark.
+//!
+//! The heap lives in a reserved virtual range of `HEAP_MAX_SIZE` bytes of
+//! which only the first `HEAP_SIZE` are mapped at boot. When an allocation
+//! does not fit, more frames are mapped at the top of the heap and the heap
+//! is extended, up to a configurable limit. The range is a `vmalloc` region,
+//! so a guard page follows it.
+//!
+//! Small allocations are served by the slab caches in front of the heap.
+//! When the heap cannot grow for lack of frames, memory reclaim runs and the
+//! allocation is retried for as long as reclaim frees something. A request
+//! that would take the heap past its limit only shrinks the caches, since
+//! terminating processes frees frames, not room under the limit.
+//!
+//! With the `heap-tracking` feature, every allocation is also recorded by
+//! `memory::tracking` for leak hunting.
+
+use super::slab::{self, SlabCache, SIZE_CLASSES};
+use super::{paging, pressure, MemoryFlags, HEAP_MAX_SIZE, PAGE_SIZE};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::{structures::paging::Page, VirtAddr};
+
+/// Minimum number of bytes mapped each time the heap grows
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+/// Why the heap could not grow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GrowError {
+    /// The request does not fit below the heap limit
+    OverLimit,
+    /// No frames could be mapped
+    OutOfFrames,
+}
+
+/// Global kernel heap with usage accounting
+pub struct KernelHeap {
+    heap: Mutex<Heap>,
+    size_caches: [Mutex<SlabCache>; SIZE_CLASSES.len()],
+    peak_used: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl KernelHeap {
+    /// Create an empty heap; `init` must be called before the first allocation
+    pub const fn empty() -> Self {
+        Self {
+            heap: Mutex::new(Heap::empty()),
+            size_caches: [
+                Mutex::new(SlabCache::new("kmalloc-16", 16, 16)),
+                Mutex::new(SlabCache::new("kmalloc-32", 32, 32)),
+                Mutex::new(SlabCache::new("kmalloc-64", 64, 64)),
+                Mutex::new(SlabCache::new("kmalloc-128", 128, 128)),
+                Mutex::new(SlabCache::new("kmalloc-256", 256, 256)),
+                Mutex::new(SlabCache::new("kmalloc-512", 512, 512)),
+                Mutex::new(SlabCache::new("kmalloc-1024", 1024, 1024)),
+                Mutex::new(SlabCache::new("kmalloc-2048", 2048, 2048)),
+            ],
+            peak_used: AtomicUsize::new(0),
+            limit: AtomicUsize::new(HEAP_MAX_SIZE),
+        }
+    }
+
+    /// Hand the mapped range `[start, start + size)` to the heap
+    ///
+    /// # Safety
+    /// The range must be mapped, writable and unused, and this function must
+    /// only be called once.
+    pub unsafe fn init(&self, start: *mut u8, size: usize) {
+        self.heap.lock().init(start, size);
+    }
+
+    /// Current size of the heap in bytes
+    pub fn size(&self) -> usize {
+        self.heap.lock().size()
+    }
+
+    /// Bytes currently allocated from the heap
+    pub fn used(&self) -> usize {
+        self.heap.lock().used()
+    }
+
+    /// Bytes currently allocated, or `None` if the heap is locked
+    pub fn try_used(&self) -> Option<usize> {
+        self.heap.try_lock().map(|heap| heap.used())
+    }
+
+    /// Highest number of bytes ever allocated at once
+    pub fn peak_used(&self) -> usize {
+        self.peak_used.load(Ordering::Relaxed)
+    }
+
+    /// General-purpose caches serving small allocations
+    pub fn size_caches(&self) -> &[Mutex<SlabCache>] {
+        &self.size_caches
+    }
+
+    /// Size the heap may grow to
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Map enough new pages at the top of the heap to satisfy `layout`
+    fn grow(&self, heap: &mut Heap, layout: Layout) -> Result<(), GrowError> {
+        // Worst case the allocation needs padding for alignment and a hole header
+        let required = align_up(layout.size() + layout.align() + 2 * core::mem::size_of::<usize>(), PAGE_SIZE);
+        let available = self.limit().saturating_sub(heap.size());
+        if required > available || heap.size() == 0 {
+            return Err(GrowError::OverLimit);
+        }
+        let pages = required.max(HEAP_GROWTH_STEP).min(available) / PAGE_SIZE;
+
+        let start_page = Page::containing_address(VirtAddr::from_ptr(heap.top()));
+        let mapped = super::try_with_memory(|mapper, frame_allocator| {
+            paging::map_allocated_range(mapper, frame_allocator, start_page, pages, MemoryFlags::KERNEL_DATA)
+        });
+        if mapped.is_err() {
+            return Err(GrowError::OutOfFrames);
+        }
+
+        unsafe { heap.extend(pages * PAGE_SIZE) };
+        Ok(())
+    }
+
+    /// Allocate from the heap without reclaiming memory on failure
+    fn try_alloc(&self, layout: Layout) -> Result<*mut u8, GrowError> {
+        let mut heap = self.heap.lock();
+        let allocation = match heap.allocate_first_fit(layout) {
+            Ok(allocation) => allocation,
+            Err(()) => {
+                self.grow(&mut heap, layout)?;
+                // Growth made room for the worst case, but fail the same way if it still does not fit
+                heap.allocate_first_fit(layout).map_err(|()| GrowError::OutOfFrames)?
+            }
+        };
+        self.peak_used.fetch_max(heap.used(), Ordering::Relaxed);
+        Ok(allocation.as_ptr())
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Slabs are allocated through `alloc` itself, which reclaims for them
+        let ptr = match slab::size_class(layout) {
+            Some(class) => slab::allocate_from(&self.size_caches[class]).map_or(ptr::null_mut(), NonNull::as_ptr),
+            None => loop {
+                let reclaimed = match self.try_alloc(layout) {
+                    Ok(ptr) => break ptr,
+                    Err(GrowError::OverLimit) => pressure::shrink_caches(),
+                    Err(GrowError::OutOfFrames) => pressure::reclaim(),
+                };
+                if !reclaimed {
+                    break ptr::null_mut();
+                }
+            },
+        };
+        #[cfg(feature = "heap-tracking")]
+        if !ptr.is_null() {
+            super::tracking::record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return;
+        };
+        #[cfg(feature = "heap-tracking")]
+        super::tracking::record_dealloc(ptr.as_ptr());
+        match slab::size_class(layout) {
+            Some(class) => self.size_caches[class].lock().deallocate(ptr),
+            None => self.heap.lock().deallocate(ptr, layout),
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
@@ -0,0 +1,421 @@
+//! Memory management subsystem for KewveOS
+//! 
+//! This module provides enterprise-grade memory management with proper
+//! page frame allocation, virtual memory management, and heap allocation.
+
+pub mod address_space;
+pub mod dma;
+pub mod fault;
+pub mod frame_allocator;
+pub mod frame_refs;
+pub mod heap;
+pub mod paging;
+pub mod pressure;
+pub mod protection;
+pub mod shared;
+pub mod slab;
+pub mod stack;
+#[cfg(feature = "heap-tracking")]
+pub mod tracking;
+pub mod vma;
+pub mod vmalloc;
+
+pub use address_space::AddressSpace;
+pub use dma::DmaBuffer;
+pub use frame_allocator::{BootInfoFrameAllocator, MemoryZone, RegionStats};
+pub use heap::KernelHeap;
+pub use paging::MemoryFlags;
+pub use fault::handle_page_fault;
+pub use shared::{SharedMemory, SharedMemoryHandle};
+pub use slab::{CacheStats, ObjectCache};
+pub use stack::KernelStack;
+pub use vma::{AreaKind, VirtualMemoryArea};
+
+use x86_64::{
+    structures::paging::{OffsetPageTable, Page, PageTable, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+use bootloader::bootinfo::MemoryMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Initial kernel heap size - 1MB mapped at boot
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+/// Virtual range reserved for the kernel heap; also the default growth cap
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+/// Size of a standard page
+pub const PAGE_SIZE: usize = 4096;
+
+/// Memory management errors following enterprise error handling standards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// Out of physical memory
+    OutOfMemory,
+    /// Invalid virtual address
+    InvalidVirtualAddress(VirtAddr),
+    /// Invalid physical address  
+    InvalidPhysicalAddress(PhysAddr),
+    /// Page mapping failed
+    MappingFailed,
+    /// Frame allocation failed
+    FrameAllocationFailed,
+    /// Heap initialization failed
+    HeapInitializationFailed,
+    /// Memory management has not been initialized yet
+    NotInitialized,
+    /// The page is already mapped
+    PageAlreadyMapped(VirtAddr),
+    /// A mapping was requested both writable and executable
+    WritableAndExecutable,
+    /// No shared memory object has this handle
+    InvalidHandle(u64),
+    /// A shared memory object with this name already exists
+    AlreadyExists,
+    /// The requested permissions exceed those of the object
+    PermissionDenied,
+    /// A size of zero or otherwise unusable size was requested
+    InvalidSize(usize),
+}
+
+impl core::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MemoryError::OutOfMemory => write!(f, "Out of physical memory"),
+            MemoryError::InvalidVirtualAddress(addr) => write!(f, "Invalid virtual address: {:#x}", addr.as_u64()),
+            MemoryError::InvalidPhysicalAddress(addr) => write!(f, "Invalid physical address: {:#x}", addr.as_u64()),
+            MemoryError::MappingFailed => write!(f, "Page mapping operation failed"),
+            MemoryError::FrameAllocationFailed => write!(f, "Physical frame allocation failed"),
+            MemoryError::HeapInitializationFailed => write!(f, "Kernel heap initialization failed"),
+            MemoryError::NotInitialized => write!(f, "Memory management not initialized"),
+            MemoryError::PageAlreadyMapped(addr) => write!(f, "Page already mapped: {:#x}", addr.as_u64()),
+            MemoryError::WritableAndExecutable => write!(f, "Mapping may not be both writable and executable"),
+            MemoryError::InvalidHandle(handle) => write!(f, "Invalid shared memory handle: {}", handle),
+            MemoryError::AlreadyExists => write!(f, "Shared memory object already exists"),
+            MemoryError::PermissionDenied => write!(f, "Permissions exceed those of the shared memory object"),
+            MemoryError::InvalidSize(size) => write!(f, "Invalid size: {}", size),
+        }
+    }
+}
+
+/// Global frame allocator instance
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Mapper for the active kernel page tables
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Run `f` with the kernel page table mapper and the frame allocator
+///
+/// Locks are always taken in the order mapper, then frame allocator, with
+/// interrupts disabled so that an interrupt handler can never deadlock on them.
+pub fn with_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> Result<R, MemoryError>,
+) -> Result<R, MemoryError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => f(mapper, frame_allocator),
+            _ => Err(MemoryError::NotInitialized),
+        }
+    })
+}
+
+/// Like `with_memory`, but fails instead of spinning if either lock is held
+///
+/// Used on paths that may be reached while the caller already holds the locks,
+/// such as heap growth triggered from inside a mapping operation.
+fn try_with_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> Result<R, MemoryError>,
+) -> Result<R, MemoryError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.try_lock().ok_or(MemoryError::MappingFailed)?;
+        let mut frame_allocator = FRAME_ALLOCATOR.try_lock().ok_or(MemoryError::FrameAllocationFailed)?;
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => f(mapper, frame_allocator),
+            _ => Err(MemoryError::NotInitialized),
+        }
+    })
+}
+
+/// Whether any lock needed to map, unmap or free memory is held
+///
+/// On a single CPU a held lock belongs to the current call chain or to the
+/// code it interrupted, so freeing memory now would deadlock.
+pub(crate) fn memory_locks_held() -> bool {
+    MAPPER.is_locked()
+        || FRAME_ALLOCATOR.is_locked()
+        || frame_refs::is_locked()
+        || vmalloc::is_locked()
+        || shared::is_locked()
+}
+
+/// Run `f` with only the frame allocator locked, with interrupts disabled
+pub fn with_frame_allocator<R>(
+    f: impl FnOnce(&mut BootInfoFrameAllocator) -> Result<R, MemoryError>,
+) -> Result<R, MemoryError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .ok_or(MemoryError::NotInitialized)
+            .and_then(f)
+    })
+}
+
+/// Like `with_frame_allocator`, but fails instead of spinning if the lock is held
+fn try_with_frame_allocator<R>(
+    f: impl FnOnce(&mut BootInfoFrameAllocator) -> Result<R, MemoryError>,
+) -> Result<R, MemoryError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .try_lock()
+            .ok_or(MemoryError::FrameAllocationFailed)?
+            .as_mut()
+            .ok_or(MemoryError::NotInitialized)
+            .and_then(f)
+    })
+}
+
+/// Virtual address at which all physical memory is mapped
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Translate a physical address into its address in the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Limit how far the kernel heap may grow, in bytes
+///
+/// The limit is clamped to `HEAP_MAX_SIZE`, the size of the reserved range,
+/// and rounded down to whole pages, since the heap grows a page at a time.
+pub fn set_heap_limit(limit: usize) {
+    crate::ALLOCATOR.set_limit(limit.min(HEAP_MAX_SIZE) / PAGE_SIZE * PAGE_SIZE);
+}
+
+/// Build an `OffsetPageTable` over the currently active level 4 page table
+///
+/// # Safety
+/// The caller must guarantee that the complete physical memory is mapped at
+/// `physical_memory_offset`. This function must only be called once to avoid
+/// aliasing `&mut` references to the page table.
+pub unsafe fn init_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Returns a mutable reference to the active level 4 table
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+/// Initialize the memory management subsystem
+/// 
+/// This function must be called early in kernel initialization with proper
+/// memory map information from the bootloader.
+///
+/// # Safety
+/// The caller must guarantee that the complete physical memory is mapped at
+/// `physical_memory_offset` and that the memory map is valid.
+pub unsafe fn init_memory_management(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+    mapper: OffsetPageTable<'static>,
+) -> Result<(), MemoryError> {
+    // Enforce NX before anything is mapped
+    if !protection::enable_no_execute() {
+        crate::serial_println!("Warning: CPU lacks NX support, data pages remain executable");
+    }
+
+    // Initialize frame allocator and hand the page tables to the memory subsystem
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::init(memory_map, physical_memory_offset)?);
+    *MAPPER.lock() = Some(mapper);
+    protection::protect_kernel_image()?;
+    let physical_memory_size = memory_map.iter().map(|region| region.range.end_addr()).max().unwrap_or(0);
+    protection::protect_physical_memory_map(physical_memory_offset, physical_memory_size)?;
+    address_space::init();
+    vmalloc::init()?;
+    stack::init()?;
+
+    // Reserve the heap range and map its initial pages
+    let heap_start = vmalloc::reserve("kernel heap", HEAP_MAX_SIZE, PAGE_SIZE, MemoryFlags::KERNEL_DATA)?;
+    let heap_pages = HEAP_SIZE / PAGE_SIZE;
+    with_memory(|mapper, frame_allocator| {
+        paging::map_allocated_range(mapper, frame_allocator, Page::containing_address(heap_start), heap_pages, MemoryFlags::KERNEL_DATA)
+    })?;
+
+    // Initialize heap allocator
+    init_heap(heap_start)?;
+
+    Ok(())
+}
+
+/// Initialize the kernel heap with proper memory mapping
+/// 
+/// This function must only be called once the heap range has been mapped.
+fn init_heap(heap_start: VirtAddr) -> Result<(), MemoryError> {
+    unsafe {
+        crate::ALLOCATOR.init(heap_start.as_mut_ptr(), HEAP_SIZE);
+    }
+    
+    // Test heap allocation to ensure it's working
+    test_heap_allocation()?;
+    
+    Ok(())
+}
+
+/// Test heap allocation to ensure memory management is working correctly
+fn test_heap_allocation() -> Result<(), MemoryError> {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    // Test basic allocation
+    let test_box = Box::new(42u64);
+    if *test_box != 42 {
+        return Err(MemoryError::HeapInitializationFailed);
+    }
+
+    // Test vector allocation
+    let mut test_vec = Vec::new();
+    for i in 0..100 {
+        test_vec.push(i);
+    }
+    
+    if test_vec.len() != 100 || test_vec[99] != 99 {
+        return Err(MemoryError::HeapInitializationFailed);
+    }
+
+    Ok(())
+}
+
+/// Memory statistics for monitoring and debugging
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// Physical memory described by the bootloader memory map
+    pub total_memory: u64,
+    /// Physical memory not available for allocation
+    pub used_memory: u64,
+    /// Physical memory available for allocation
+    pub free_memory: u64,
+    /// Highest amount of usable memory ever allocated at once
+    pub peak_used_memory: u64,
+    pub heap_size: usize,
+    pub heap_used: usize,
+    pub heap_peak_used: usize,
+    /// Size the heap may grow to
+    pub heap_limit: usize,
+    /// Breakdown of physical memory by region type
+    pub regions: RegionStats,
+}
+
+/// Get current memory statistics
+pub fn get_memory_stats() -> MemoryStats {
+    let (regions, free_frames, peak_used_frames) = x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map(|allocator| (allocator.regions(), allocator.free_frames(), allocator.peak_used_frames()))
+            .unwrap_or_default()
+    });
+    let total_memory = regions.total();
+    let free_memory = free_frames as u64 * frame_allocator::FRAME_SIZE;
+
+    MemoryStats {
+        total_memory,
+        used_memory: total_memory - free_memory,
+        free_memory,
+        peak_used_memory: peak_used_frames as u64 * frame_allocator::FRAME_SIZE,
+        heap_size: crate::ALLOCATOR.size(),
+        heap_used: crate::ALLOCATOR.used(),
+        heap_peak_used: crate::ALLOCATOR.peak_used(),
+        heap_limit: crate::ALLOCATOR.limit(),
+        regions,
+    }
+}
+
+/// Platform-specific memory management trait
+pub trait PlatformMemoryManager {
+    type Error;
+    
+    /// Initialize platform-specific memory management
+    fn init(&mut self) -> Result<(), Self::Error>;
+    
+    /// Allocate physically contiguous pages
+    fn allocate_pages(&mut self, count: usize) -> Result<PhysAddr, Self::Error>;
+    
+    /// Deallocate physical pages
+    fn deallocate_pages(&mut self, addr: PhysAddr, count: usize) -> Result<(), Self::Error>;
+    
+    /// Map virtual pages to physical pages
+    fn map_pages(&mut self, virt: VirtAddr, phys: PhysAddr, count: usize, flags: MemoryFlags) -> Result<(), Self::Error>;
+    
+    /// Unmap virtual pages
+    ///
+    /// The physical pages stay allocated and must be released separately.
+    fn unmap_pages(&mut self, virt: VirtAddr, count: usize) -> Result<(), Self::Error>;
+}
+
+/// x86_64 specific memory manager implementation
+///
+/// Operates on the global frame allocator and the active kernel page tables.
+#[cfg(target_arch = "x86_64")]
+pub struct X86MemoryManager;
+
+#[cfg(target_arch = "x86_64")]
+impl X86MemoryManager {
+    pub const fn new() -> Self {
+        Self
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Default for X86MemoryManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PlatformMemoryManager for X86MemoryManager {
+    type Error = MemoryError;
+    
+    fn init(&mut self) -> Result<(), Self::Error> {
+        // Both the frame allocator and the mapper must be set up by init_memory_management
+        with_memory(|_, _| Ok(()))
+    }
+    
+    fn allocate_pages(&mut self, count: usize) -> Result<PhysAddr, Self::Error> {
+        with_memory(|_, frame_allocator| {
+            frame_allocator
+                .allocate_contiguous(count, 1)
+                .map(|frame| frame.start_address())
+                .ok_or(MemoryError::OutOfMemory)
+        })
+    }
+    
+    fn deallocate_pages(&mut self, addr: PhysAddr, count: usize) -> Result<(), Self::Error> {
+        let frame = PhysFrame::from_start_address(addr)
+            .map_err(|_| MemoryError::InvalidPhysicalAddress(addr))?;
+        with_memory(|_, frame_allocator| frame_allocator.deallocate_contiguous(frame, count))
+    }
+    
+    fn map_pages(&mut self, virt: VirtAddr, phys: PhysAddr, count: usize, flags: MemoryFlags) -> Result<(), Self::Error> {
+        let page = Page::from_start_address(virt)
+            .map_err(|_| MemoryError::InvalidVirtualAddress(virt))?;
+        let frame = PhysFrame::from_start_address(phys)
+            .map_err(|_| MemoryError::InvalidPhysicalAddress(phys))?;
+        with_memory(|mapper, frame_allocator| {
+            paging::map_range(mapper, frame_allocator, page, frame, count, flags)
+        })
+    }
+    
+    fn unmap_pages(&mut self, virt: VirtAddr, count: usize) -> Result<(), Self::Error> {
+        let page = Page::from_start_address(virt)
+            .map_err(|_| MemoryError::InvalidVirtualAddress(virt))?;
+        with_memory(|mapper, frame_allocator| paging::unmap_range(mapper, frame_allocator, page, count))
+    }
+}
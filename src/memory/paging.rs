@@ -0,0 +1,453 @@
+//! Page table manipulation helpers for KewveOS
+//!
+//! Thin wrappers over the `x86_64` mapper that translate between the portable
+//! `MemoryFlags` type and hardware page table flags, and that report failures
+//! as `MemoryError`.
+//!
+//! Ranges are mapped with 1 GiB and 2 MiB pages wherever the virtual and
+//! physical addresses are suitably aligned, and with 4 KiB pages elsewhere.
+//! Unmapping part of a huge page splits it into smaller pages first.
+
+use super::{phys_to_virt, BootInfoFrameAllocator, MemoryError, PAGE_SIZE};
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Software bit marking a leaf entry whose frame is owned by its address space
+///
+/// Owned frames are freed when they are unmapped or the address space is destroyed.
+pub const OWNED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Software bit marking a read-only leaf entry that becomes private and writable on write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Software bit marking a leaf entry that maps a shared memory frame
+///
+/// Shared frames stay writable across a fork instead of becoming copy-on-write.
+pub const SHARED_FRAME: PageTableFlags = PageTableFlags::BIT_11;
+
+/// Portable page permissions used by the memory APIs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryFlags {
+    /// Pages may be written
+    pub writable: bool,
+    /// Instructions may be fetched from the pages
+    pub executable: bool,
+    /// Pages are reachable from user mode
+    pub user_accessible: bool,
+    /// Caching is disabled (for device memory)
+    pub uncached: bool,
+}
+
+impl MemoryFlags {
+    /// Kernel read-only data
+    pub const KERNEL_READ_ONLY: Self = Self {
+        writable: false,
+        executable: false,
+        user_accessible: false,
+        uncached: false,
+    };
+
+    /// Kernel read/write data
+    pub const KERNEL_DATA: Self = Self {
+        writable: true,
+        ..Self::KERNEL_READ_ONLY
+    };
+
+    /// Kernel code
+    pub const KERNEL_CODE: Self = Self {
+        executable: true,
+        ..Self::KERNEL_READ_ONLY
+    };
+
+    /// Uncached kernel read/write mapping for device registers
+    pub const DEVICE: Self = Self {
+        uncached: true,
+        ..Self::KERNEL_DATA
+    };
+
+    /// Same permissions, reachable from user mode
+    pub const fn user(self) -> Self {
+        Self {
+            user_accessible: true,
+            ..self
+        }
+    }
+
+    /// Reject permissions that are both writable and executable (W^X)
+    pub fn validate(self) -> Result<Self, MemoryError> {
+        if self.writable && self.executable {
+            return Err(MemoryError::WritableAndExecutable);
+        }
+        Ok(self)
+    }
+
+    /// Translate into hardware page table flags
+    pub fn page_table_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.user_accessible {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.uncached {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
+        // The NX bit is reserved (and faults) unless EFER.NXE is set
+        if !self.executable && Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Map `count` pages starting at `page` to the frames starting at `frame`
+///
+/// Huge pages are used where alignment allows. On failure, pages mapped by
+/// this call are unmapped again.
+pub fn map_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    frame: PhysFrame,
+    count: usize,
+    flags: MemoryFlags,
+) -> Result<(), MemoryError> {
+    let flags = flags.validate()?.page_table_flags();
+    let size = (count * PAGE_SIZE) as u64;
+    let mut offset = 0;
+    while offset < size {
+        let virt = page.start_address() + offset;
+        let phys = frame.start_address() + offset;
+        let step = largest_page_size(mapper, virt, phys, size - offset);
+        let result = match step {
+            Size1GiB::SIZE => map_page::<Size1GiB>(mapper, frame_allocator, virt, phys, flags),
+            Size2MiB::SIZE => map_page::<Size2MiB>(mapper, frame_allocator, virt, phys, flags),
+            _ => map_page::<Size4KiB>(mapper, frame_allocator, virt, phys, flags),
+        };
+        if let Err(error) = result {
+            unmap_range(mapper, frame_allocator, page, (offset / PAGE_SIZE as u64) as usize)?;
+            return Err(error);
+        }
+        offset += step;
+    }
+    Ok(())
+}
+
+/// Map `count` pages starting at `page`, backing each with a fresh frame
+///
+/// Where `page` is 2 MiB aligned, whole 2 MiB pages are backed by contiguous
+/// frames if the frame allocator can provide them.
+pub fn map_allocated_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    count: usize,
+    flags: MemoryFlags,
+) -> Result<(), MemoryError> {
+    const HUGE_FRAMES: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+    flags.validate()?;
+    let mut mapped = 0;
+    while mapped < count {
+        let next = page + mapped as u64;
+        let huge = next.start_address().is_aligned(Size2MiB::SIZE)
+            && count - mapped >= HUGE_FRAMES
+            && !links_table(mapper, next.start_address(), Size2MiB::SIZE);
+        let contiguous = huge.then(|| frame_allocator.allocate_contiguous(HUGE_FRAMES, HUGE_FRAMES)).flatten();
+        // Fall back to a single frame if no aligned 2 MiB block is free
+        let (frame, frames) = match contiguous {
+            Some(frame) => (Some(frame), HUGE_FRAMES),
+            None => (frame_allocator.allocate_frame(), 1),
+        };
+        let result = frame.ok_or(MemoryError::OutOfMemory).and_then(|frame| {
+            map_range(mapper, frame_allocator, next, frame, frames, flags)
+                .inspect_err(|_| free_frames(frame_allocator, frame.start_address(), frames as u64 * Size4KiB::SIZE))
+        });
+        if let Err(error) = result {
+            unmap_and_free_range(mapper, frame_allocator, page, mapped)?;
+            return Err(error);
+        }
+        mapped += frames;
+    }
+    Ok(())
+}
+
+/// Unmap `count` pages starting at `page` and free their backing frames
+pub fn unmap_and_free_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    count: usize,
+) -> Result<(), MemoryError> {
+    unmap_with(mapper, frame_allocator, page, count, free_frames)
+}
+
+/// Unmap `count` pages starting at `page`
+///
+/// The backing frames are not freed; they belong to whoever mapped them.
+pub fn unmap_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    count: usize,
+) -> Result<(), MemoryError> {
+    unmap_with(mapper, frame_allocator, page, count, |_, _, _| {})
+}
+
+/// Replace the huge page mapping `addr` with a table of smaller pages
+///
+/// A 2 MiB page becomes 512 4 KiB pages and a 1 GiB page becomes 512 2 MiB
+/// pages, all with the original permissions. Does nothing if `addr` is
+/// already mapped by a 4 KiB page.
+pub fn split_huge_page(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    addr: VirtAddr,
+) -> Result<(), MemoryError> {
+    let level_4 = mapper.level_4_table();
+    let level_3 = present_table(&level_4[addr.p4_index()]).ok_or(MemoryError::InvalidVirtualAddress(addr))?;
+    let entry_3 = &mut level_3[addr.p3_index()];
+    if entry_3.is_unused() {
+        return Err(MemoryError::InvalidVirtualAddress(addr));
+    }
+
+    let (entry, child_size) = if entry_3.flags().contains(PageTableFlags::HUGE_PAGE) {
+        (entry_3, Size2MiB::SIZE)
+    } else {
+        let level_2 = unsafe { table_at(PhysFrame::containing_address(entry_3.addr())) };
+        let entry_2 = &mut level_2[addr.p2_index()];
+        if entry_2.is_unused() {
+            return Err(MemoryError::InvalidVirtualAddress(addr));
+        }
+        if !entry_2.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(());
+        }
+        (entry_2, Size4KiB::SIZE)
+    };
+
+    let flags = entry.flags();
+    let base = entry.addr();
+    let mut child_flags = flags;
+    if child_size == Size4KiB::SIZE {
+        // Bit 7 is PAT rather than the page size in 4 KiB entries
+        child_flags.remove(PageTableFlags::HUGE_PAGE);
+    }
+
+    let table_frame = frame_allocator.allocate_frame().ok_or(MemoryError::FrameAllocationFailed)?;
+    let table = unsafe { table_at(table_frame) };
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(base + i as u64 * child_size, child_flags);
+    }
+
+    // Permissions are enforced by the new leaf entries
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    entry.set_frame(table_frame, table_flags);
+    tlb::flush(addr);
+    Ok(())
+}
+
+/// Unmap a range page by page, handing each unmapped physical range to `release`
+fn unmap_with(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    count: usize,
+    release: impl Fn(&mut BootInfoFrameAllocator, PhysAddr, u64),
+) -> Result<(), MemoryError> {
+    let end = page.start_address() + (count * PAGE_SIZE) as u64;
+    let mut addr = page.start_address();
+    while addr < end {
+        let (phys, size) = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => (frame.start_address(), frame.size()),
+            _ => return Err(MemoryError::InvalidVirtualAddress(addr)),
+        };
+        if size > Size4KiB::SIZE && (!addr.is_aligned(size) || end - addr < size) {
+            // Only part of this huge page is being unmapped
+            split_huge_page(mapper, frame_allocator, addr)?;
+            continue;
+        }
+
+        match size {
+            Size1GiB::SIZE => unmap_page::<Size1GiB>(mapper, addr)?,
+            Size2MiB::SIZE => unmap_page::<Size2MiB>(mapper, addr)?,
+            _ => unmap_page::<Size4KiB>(mapper, addr)?,
+        }
+        release(frame_allocator, phys, size);
+        addr += size;
+    }
+    Ok(())
+}
+
+fn map_page<S: PageSize + core::fmt::Debug>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), MemoryError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+    let flags = if S::SIZE == Size4KiB::SIZE { flags } else { flags | PageTableFlags::HUGE_PAGE };
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => Err(map_to_error(error)),
+    }
+}
+
+fn unmap_page<S: PageSize + core::fmt::Debug>(
+    mapper: &mut OffsetPageTable<'static>,
+    addr: VirtAddr,
+) -> Result<(), MemoryError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let (_, flush) = Mapper::<S>::unmap(mapper, Page::<S>::containing_address(addr))
+        .map_err(|error| unmap_error(addr, error))?;
+    flush.flush();
+    Ok(())
+}
+
+/// Largest page size usable at `virt`/`phys` for a range of `remaining` bytes
+///
+/// Where the range was mapped with smaller pages before, the table that held
+/// them is still linked, so pages of that size are used again.
+fn largest_page_size(mapper: &mut OffsetPageTable<'static>, virt: VirtAddr, phys: PhysAddr, remaining: u64) -> u64 {
+    let mut fits = |size: u64| {
+        virt.is_aligned(size) && phys.is_aligned(size) && remaining >= size && !links_table(mapper, virt, size)
+    };
+    if fits(Size1GiB::SIZE) && supports_1gib_pages() {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Whether the entry a page of `size` at `virt` would take links a lower
+/// level table instead
+fn links_table(mapper: &mut OffsetPageTable<'static>, virt: VirtAddr, size: u64) -> bool {
+    let links = |entry: &PageTableEntry| !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE);
+    let Some(level_3) = present_table(&mapper.level_4_table()[virt.p4_index()]) else {
+        return false;
+    };
+    let entry_3 = &level_3[virt.p3_index()];
+    if size == Size1GiB::SIZE || !links(entry_3) {
+        return links(entry_3);
+    }
+    let level_2 = unsafe { table_at(PhysFrame::containing_address(entry_3.addr())) };
+    size == Size2MiB::SIZE && links(&level_2[virt.p2_index()])
+}
+
+/// Whether the CPU can map 1 GiB pages (CPUID leaf 0x8000_0001, EDX bit 26)
+fn supports_1gib_pages() -> bool {
+    let edx = crate::platform::x86_64::cpuid(0x8000_0001).edx;
+    edx & (1 << 26) != 0
+}
+
+fn free_frames(frame_allocator: &mut BootInfoFrameAllocator, start: PhysAddr, size: u64) {
+    for i in 0..size / Size4KiB::SIZE {
+        let frame = PhysFrame::containing_address(start + i * Size4KiB::SIZE);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+fn present_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    if entry.is_unused() {
+        None
+    } else {
+        Some(unsafe { table_at(PhysFrame::containing_address(entry.addr())) })
+    }
+}
+
+/// The page table stored in `frame`, accessed through the physical memory mapping
+///
+/// # Safety
+/// `frame` must hold a page table, and the caller must hold the lock that
+/// protects it.
+pub(super) unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// Whether `addr` is mapped in the active page tables
+///
+/// Reads the tables directly without taking any lock, so fault and panic
+/// paths can use it. Always `false` before memory management is initialized.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    if phys_to_virt(PhysAddr::new(0)).as_u64() == 0 {
+        return false;
+    }
+    let (mut frame, _) = Cr3::read();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in indexes.into_iter().enumerate() {
+        let table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // Level 3 and level 2 entries may map 1 GiB and 2 MiB pages directly
+        if level == 3 || (level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+            return true;
+        }
+        frame = PhysFrame::containing_address(entry.addr());
+    }
+    false
+}
+
+/// Fill a physical frame with zeroes through the physical memory mapping
+pub fn zero_frame(frame: PhysFrame) {
+    let virt = super::phys_to_virt(frame.start_address());
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+}
+
+/// Make sure the level 4 entry covering `addr` points to a page table
+///
+/// Address spaces copy the kernel's level 4 entries when they are created,
+/// so kernel ranges populated later need their entry in place up front.
+pub fn reserve_level_4_entry(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    addr: VirtAddr,
+) -> Result<(), MemoryError> {
+    let entry = &mut mapper.level_4_table()[addr.p4_index()];
+    if !entry.is_unused() {
+        return Ok(());
+    }
+    let frame = frame_allocator.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+    zero_frame(frame);
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    Ok(())
+}
+
+fn unmap_error(addr: VirtAddr, error: UnmapError) -> MemoryError {
+    match error {
+        UnmapError::PageNotMapped => MemoryError::InvalidVirtualAddress(addr),
+        _ => MemoryError::MappingFailed,
+    }
+}
+
+fn map_to_error<S: PageSize>(error: MapToError<S>) -> MemoryError {
+    match error {
+        MapToError::FrameAllocationFailed => MemoryError::FrameAllocationFailed,
+        _ => MemoryError::MappingFailed,
+    }
+}
@@ -0,0 +1,163 @@
+//! Memory pressure handling for KewveOS
+//!
+//! Watches free physical frames and kernel heap headroom. When memory runs
+//! low, reclaim proceeds in stages:
+//!
+//! 1. Registered caches and shrinkers give back memory they can spare.
+//! 2. The lowest-priority process is terminated through the scheduler.
+//!    This stage is skipped while the memory locks are held, since tearing
+//!    down the process would need them.
+//!
+//! Only when neither stage frees anything does an allocation fail, and the
+//! kernel panics only if that allocation was one it cannot do without.
+
+use super::{slab, FRAME_ALLOCATOR};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Free frames below which memory is considered low (4 MiB)
+const LOW_FREE_FRAMES: usize = 1024;
+/// Free frames below which memory is considered critical (1 MiB)
+const CRITICAL_FREE_FRAMES: usize = 256;
+/// Heap usage, in percent of the heap limit, considered low
+const LOW_HEAP_PERCENT: usize = 90;
+/// Heap usage, in percent of the heap limit, considered critical
+const CRITICAL_HEAP_PERCENT: usize = 97;
+/// Maximum number of registered shrinkers
+const MAX_SHRINKERS: usize = 16;
+
+/// How short of memory the system is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    /// Plenty of memory available
+    Normal,
+    /// Caches should give back what they can
+    Low,
+    /// Allocations are about to fail; processes may be terminated
+    Critical,
+}
+
+/// A callback that releases memory and returns the number of bytes freed
+///
+/// Shrinkers run on the allocation failure path and must not allocate.
+#[derive(Clone, Copy)]
+pub struct Shrinker {
+    pub name: &'static str,
+    pub shrink: fn() -> usize,
+}
+
+/// Registered shrinkers; a fixed table so reclaim never needs the heap
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+
+/// Set while reclaim runs, so allocation failures inside it fail fast
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Number of processes terminated to free memory
+static PROCESSES_RECLAIMED: AtomicU64 = AtomicU64::new(0);
+
+/// Register a shrinker to be called under memory pressure
+pub fn register_shrinker(shrinker: Shrinker) -> Result<(), super::MemoryError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut shrinkers = SHRINKERS.lock();
+        let slot = shrinkers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(super::MemoryError::OutOfMemory)?;
+        *slot = Some(shrinker);
+        Ok(())
+    })
+}
+
+/// Current memory pressure
+///
+/// Locks that are held elsewhere are treated as "no information" rather than
+/// waited on, so this can be called from any context.
+pub fn pressure_level() -> PressureLevel {
+    let free_frames = FRAME_ALLOCATOR
+        .try_lock()
+        .and_then(|allocator| allocator.as_ref().map(|allocator| allocator.free_frames()))
+        .unwrap_or(usize::MAX);
+    let heap_percent = crate::ALLOCATOR
+        .try_used()
+        .map(|used| used * 100 / crate::ALLOCATOR.limit().max(1))
+        .unwrap_or(0);
+
+    if free_frames < CRITICAL_FREE_FRAMES || heap_percent >= CRITICAL_HEAP_PERCENT {
+        PressureLevel::Critical
+    } else if free_frames < LOW_FREE_FRAMES || heap_percent >= LOW_HEAP_PERCENT {
+        PressureLevel::Low
+    } else {
+        PressureLevel::Normal
+    }
+}
+
+/// Check memory pressure and reclaim ahead of allocation failures
+///
+/// Called periodically, for example on every process switch.
+pub fn check() {
+    match pressure_level() {
+        PressureLevel::Normal => {}
+        PressureLevel::Low => {
+            shrink();
+        }
+        PressureLevel::Critical => {
+            reclaim();
+        }
+    }
+}
+
+/// Free memory after an allocation failed
+///
+/// Returns `true` if anything was freed and the allocation is worth retrying.
+pub fn reclaim() -> bool {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        // An allocation inside reclaim failed; don't recurse
+        return false;
+    }
+
+    let freed = shrink() > 0 || reclaim_process();
+
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+/// Free memory held by caches and shrinkers only
+///
+/// Used when an allocation failed for lack of room under the heap limit,
+/// which terminating processes cannot make. Returns `true` if anything was freed.
+pub fn shrink_caches() -> bool {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    let freed = shrink() > 0;
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+/// Number of processes terminated to free memory since boot
+pub fn processes_reclaimed() -> u64 {
+    PROCESSES_RECLAIMED.load(Ordering::Relaxed)
+}
+
+/// Ask caches and shrinkers for memory; returns the number of bytes released
+fn shrink() -> usize {
+    // Copy the table out so shrinkers run without the lock held
+    let shrinkers = SHRINKERS.try_lock().map(|shrinkers| *shrinkers).unwrap_or([None; MAX_SHRINKERS]);
+    let from_shrinkers: usize = shrinkers.iter().flatten().map(|shrinker| (shrinker.shrink)()).sum();
+    slab::shrink_caches() + from_shrinkers
+}
+
+/// Terminate the lowest-priority process; returns `true` if one was terminated
+fn reclaim_process() -> bool {
+    // Dropping the victim's address space and kernel stack takes these locks
+    if super::memory_locks_held() {
+        return false;
+    }
+    match crate::process::terminate_lowest_priority() {
+        Some(_) => {
+            PROCESSES_RECLAIMED.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,139 @@
+//! W^X enforcement for KewveOS
+//!
+//! No kernel page is ever both writable and executable (NFR-11 in
+//! doc/REQUIREMENTS.md), in support of the memory safety and attack surface
+//! goals there: memory an attacker can write code into must not be runnable.
+//!
+//! At boot the NX bit is enabled, and the kernel image is remapped from its
+//! ELF program headers: code becomes read-only and executable, read-only
+//! data and writable data become non-executable. Everything mapped later
+//! goes through `MemoryFlags`, which is non-executable unless asked
+//! otherwise and refuses to be both writable and executable. The
+//! bootloader's writable mapping of all physical memory is made
+//! non-executable as a whole.
+
+use super::{with_memory, MemoryError, MemoryFlags};
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{Mapper, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+// Start of the kernel's ELF header, defined by the linker when the header is loaded
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+/// `p_type` of a loadable segment
+const PT_LOAD: u32 = 1;
+/// Segment permission bits in `p_flags`
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The start of the ELF64 file header, up to the program header fields
+#[repr(C)]
+#[allow(dead_code)]
+struct ElfHeader {
+    ident: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+}
+
+/// An ELF64 program header
+#[repr(C)]
+#[allow(dead_code)]
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+/// Enable the NX bit so that non-executable mappings are enforced
+///
+/// Must run before anything is mapped, since `MemoryFlags` only emits the
+/// NX bit once it is enabled. Returns `false` if the CPU lacks NX support.
+pub(super) fn enable_no_execute() -> bool {
+    // CPUID leaf 0x8000_0001, EDX bit 20; setting NXE without it faults
+    let edx = crate::platform::x86_64::cpuid(0x8000_0001).edx;
+    if edx & (1 << 20) == 0 {
+        return false;
+    }
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    true
+}
+
+/// Remap every loaded segment of the kernel image with its ELF permissions
+pub(super) fn protect_kernel_image() -> Result<(), MemoryError> {
+    #[allow(unused_unsafe)]
+    let base = unsafe { core::ptr::addr_of!(__ehdr_start) };
+    let header = unsafe { &*base.cast::<ElfHeader>() };
+
+    with_memory(|mapper, _| {
+        for index in 0..header.phnum as usize {
+            let offset = header.phoff as usize + index * header.phentsize as usize;
+            let segment = unsafe { &*base.add(offset).cast::<ProgramHeader>() };
+            if segment.p_type != PT_LOAD || segment.p_memsz == 0 {
+                continue;
+            }
+
+            let flags = MemoryFlags {
+                writable: segment.p_flags & PF_W != 0,
+                executable: segment.p_flags & PF_X != 0,
+                ..MemoryFlags::KERNEL_READ_ONLY
+            };
+            flags.validate()?;
+
+            let start = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.p_vaddr));
+            let end = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.p_vaddr + segment.p_memsz - 1));
+            for page in Page::range_inclusive(start, end) {
+                unsafe { mapper.update_flags(page, flags.page_table_flags()) }
+                    .map_err(|_| MemoryError::InvalidVirtualAddress(page.start_address()))?
+                    .ignore();
+            }
+        }
+        tlb::flush_all();
+        Ok(())
+    })
+}
+
+/// Make the mapping of all physical memory at `offset` non-executable
+///
+/// The NX bit is set on the level 4 entries covering the mapping, which
+/// applies it to everything below them. Entries shared with the kernel
+/// image are left alone.
+pub(super) fn protect_physical_memory_map(offset: VirtAddr, size: u64) -> Result<(), MemoryError> {
+    if !Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) || size == 0 {
+        return Ok(());
+    }
+    #[allow(unused_unsafe)]
+    let kernel_entry = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(__ehdr_start) }).p4_index();
+    let first = offset.p4_index();
+    let last = (offset + (size - 1)).p4_index();
+
+    with_memory(|mapper, _| {
+        let table = mapper.level_4_table();
+        for index in u16::from(first)..=u16::from(last) {
+            let entry = &mut table[index as usize];
+            if index == u16::from(kernel_entry) || entry.is_unused() {
+                continue;
+            }
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+        tlb::flush_all();
+        Ok(())
+    })
+}
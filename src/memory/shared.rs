@@ -0,0 +1,216 @@
+//! Shared memory objects for KewveOS
+//!
+//! A shared memory object is a set of frames that several address spaces
+//! map at once, for IPC between apps and system services. Objects are
+//! identified by a handle and optionally by a name. The creator chooses the
+//! object's permissions; other processes map it by handle with the same or
+//! fewer permissions.
+//!
+//! Every mapping is recorded as a memory area holding a reference to the
+//! object, and every mapped page holds a reference on its frame. The object
+//! disappears when its last mapping is removed, and each frame is freed
+//! once no mapping and no object refers to it any more.
+
+use super::address_space::AddressSpace;
+use super::vma::{AreaKind, VirtualMemoryArea};
+use super::{frame_refs, paging, with_frame_allocator, MemoryError, MemoryFlags, PAGE_SIZE};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    VirtAddr,
+};
+
+/// Identifier of a shared memory object
+pub type SharedMemoryHandle = u64;
+
+/// Live objects; entries do not keep their object alive
+static OBJECTS: Mutex<BTreeMap<SharedMemoryHandle, Weak<SharedMemory>>> = Mutex::new(BTreeMap::new());
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+/// A block of memory that can be mapped into several address spaces
+pub struct SharedMemory {
+    handle: SharedMemoryHandle,
+    name: Option<String>,
+    frames: Vec<PhysFrame>,
+    flags: MemoryFlags,
+}
+
+impl SharedMemory {
+    /// Create a zero-filled object of at least `size` bytes
+    ///
+    /// `flags` are the most permissive flags any mapping may use. The object
+    /// only lives as long as it is mapped or referenced, so the creator is
+    /// expected to map it before dropping the returned reference.
+    pub fn create(name: Option<String>, size: usize, flags: MemoryFlags) -> Result<Arc<Self>, MemoryError> {
+        flags.validate()?;
+        if size == 0 {
+            return Err(MemoryError::InvalidSize(size));
+        }
+
+        let pages = size.div_ceil(PAGE_SIZE);
+        let mut frames = Vec::with_capacity(pages);
+        let allocated = with_frame_allocator(|allocator| {
+            for _ in 0..pages {
+                let frame = allocator.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+                paging::zero_frame(frame);
+                frames.push(frame);
+            }
+            Ok(())
+        });
+        let object = Arc::new(SharedMemory {
+            handle: NEXT_HANDLE.fetch_add(1, Ordering::Relaxed),
+            name,
+            frames,
+            flags,
+        });
+        // On failure, dropping the object frees the frames allocated so far
+        allocated?;
+
+        // Check the name and publish the object in one step, so two creators
+        // cannot both claim it; on a clash the object is dropped unlocked
+        let inserted = without_interrupts(|| {
+            let mut objects = OBJECTS.lock();
+            if let Some(name) = object.name() {
+                if objects.values().filter_map(Weak::upgrade).any(|other| other.name() == Some(name)) {
+                    return false;
+                }
+            }
+            objects.insert(object.handle, Arc::downgrade(&object));
+            true
+        });
+        if !inserted {
+            return Err(MemoryError::AlreadyExists);
+        }
+        Ok(object)
+    }
+
+    /// Handle other processes use to map this object
+    pub fn handle(&self) -> SharedMemoryHandle {
+        self.handle
+    }
+
+    /// Name the object was created with, if any
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Size of the object in bytes
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    /// Most permissive flags a mapping may use
+    pub fn flags(&self) -> MemoryFlags {
+        self.flags
+    }
+
+    /// Frames backing the object, in order
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    /// Whether a mapping with `flags` stays within the object's permissions
+    fn permits(&self, flags: MemoryFlags) -> bool {
+        (!flags.writable || self.flags.writable) && (!flags.executable || self.flags.executable)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        without_interrupts(|| OBJECTS.lock().remove(&self.handle));
+        let _ = with_frame_allocator(|allocator| {
+            for &frame in &self.frames {
+                if frame_refs::release(frame) {
+                    unsafe { allocator.deallocate_frame(frame) };
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+impl core::fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("handle", &self.handle)
+            .field("name", &self.name)
+            .field("size", &self.size())
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+/// Find a live object by handle
+pub fn lookup(handle: SharedMemoryHandle) -> Option<Arc<SharedMemory>> {
+    without_interrupts(|| OBJECTS.lock().get(&handle).and_then(Weak::upgrade))
+}
+
+/// Whether the object table is locked
+///
+/// Inserting into it may allocate, and dropping an object locks it again,
+/// so memory reclaim must not free objects while it is held.
+pub(super) fn is_locked() -> bool {
+    OBJECTS.is_locked()
+}
+
+/// Find a live object by name
+pub fn open(name: &str) -> Option<Arc<SharedMemory>> {
+    without_interrupts(|| {
+        OBJECTS
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .find(|object| object.name() == Some(name))
+    })
+}
+
+/// Map the object `handle` refers to at `start` in `space`
+pub fn map(
+    space: &AddressSpace,
+    handle: SharedMemoryHandle,
+    start: VirtAddr,
+    flags: MemoryFlags,
+) -> Result<(), MemoryError> {
+    let object = lookup(handle).ok_or(MemoryError::InvalidHandle(handle))?;
+    map_object(space, &object, start, flags)
+}
+
+/// Map `object` at `start` in `space` with `flags`
+///
+/// `flags` may not grant more than the object's own permissions.
+pub fn map_object(
+    space: &AddressSpace,
+    object: &Arc<SharedMemory>,
+    start: VirtAddr,
+    flags: MemoryFlags,
+) -> Result<(), MemoryError> {
+    if !object.permits(flags) {
+        return Err(MemoryError::PermissionDenied);
+    }
+    let area = VirtualMemoryArea::new(
+        "shared memory",
+        start,
+        object.frames.len(),
+        flags,
+        AreaKind::Shared(object.clone()),
+    );
+    space.add_area(area)?;
+    space.map_shared_frames(start, &object.frames, flags).inspect_err(|_| {
+        let _ = space.remove_area(start);
+    })
+}
+
+/// Remove the shared mapping starting at `start` from `space`
+pub fn unmap(space: &AddressSpace, start: VirtAddr) -> Result<(), MemoryError> {
+    match space.find_area(start) {
+        Some(area) if area.start == start && matches!(area.kind, AreaKind::Shared(_)) => space.remove_area(start),
+        _ => Err(MemoryError::InvalidVirtualAddress(start)),
+    }
+}
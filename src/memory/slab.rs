@@ -0,0 +1,334 @@
+//! Slab allocator for KewveOS
+//!
+//! Small, frequently allocated kernel objects are served from caches of
+//! equally sized objects instead of the linked-list heap. Each cache carves
+//! slabs (naturally aligned blocks taken from the heap) into objects and keeps
+//! freed objects on an intrusive free list, so allocation and deallocation are
+//! O(1) and objects of one size never fragment the heap.
+//!
+//! The kernel heap routes small allocations to a set of general size-class
+//! caches. Subsystems can create their own typed `ObjectCache<T>`.
+
+use super::{MemoryError, PAGE_SIZE};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use spin::Mutex;
+
+/// Object sizes served by the general-purpose caches
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Minimum number of objects per slab; larger objects get multi-page slabs
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Header at the start of every slab
+struct SlabHeader {
+    /// Next slab with free objects
+    next: Option<NonNull<SlabHeader>>,
+    /// First free object in this slab
+    free: Option<NonNull<FreeObject>>,
+    /// Number of allocated objects in this slab
+    in_use: usize,
+}
+
+/// A free object, linked through its own storage
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Statistics for a single cache
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    /// Total number of allocations served since boot
+    pub allocations: u64,
+    /// Bytes of heap memory held by the cache's slabs
+    pub memory_bytes: usize,
+}
+
+/// A cache of equally sized objects
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    slab_size: usize,
+    /// Slabs that have at least one free object
+    partial: Option<NonNull<SlabHeader>>,
+    slabs: usize,
+    objects_in_use: usize,
+    allocations: u64,
+}
+
+// Slabs are only ever touched through the owning cache's lock
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Create an empty cache for objects of `size` bytes aligned to `align`
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align > mem::align_of::<FreeObject>() { align } else { mem::align_of::<FreeObject>() };
+        let size = if size > mem::size_of::<FreeObject>() { size } else { mem::size_of::<FreeObject>() };
+        let object_size = (size + align - 1) & !(align - 1);
+        let slab_size = if object_size * MIN_OBJECTS_PER_SLAB > PAGE_SIZE {
+            (object_size * MIN_OBJECTS_PER_SLAB).next_power_of_two()
+        } else {
+            PAGE_SIZE
+        };
+
+        Self {
+            name,
+            object_size,
+            align,
+            slab_size,
+            partial: None,
+            slabs: 0,
+            objects_in_use: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Allocate one object from the cache's existing slabs
+    ///
+    /// Returns `None` if every slab is full; see `allocate_from` for growing.
+    pub fn allocate(&mut self) -> Option<NonNull<u8>> {
+        let slab = self.partial?.as_ptr();
+        unsafe {
+            let object = (*slab).free?;
+            (*slab).free = (*object.as_ptr()).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_none() {
+                // Slab is full, stop offering it
+                self.partial = (*slab).next.take();
+            }
+
+            self.objects_in_use += 1;
+            self.allocations += 1;
+            Some(object.cast())
+        }
+    }
+
+    /// Return an object to the cache
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` on this cache and not freed since.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let slab = (ptr.as_ptr() as usize & !(self.slab_size - 1)) as *mut SlabHeader;
+        let object = ptr.cast::<FreeObject>();
+        let was_full = (*slab).free.is_none();
+
+        (*object.as_ptr()).next = (*slab).free;
+        (*slab).free = Some(object);
+        (*slab).in_use -= 1;
+        self.objects_in_use -= 1;
+
+        if was_full {
+            (*slab).next = self.partial;
+            self.partial = NonNull::new(slab);
+        }
+    }
+
+    /// Release all completely free slabs back to the heap
+    ///
+    /// Returns the number of bytes released.
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        let mut link: *mut Option<NonNull<SlabHeader>> = &mut self.partial;
+        unsafe {
+            while let Some(slab) = *link {
+                if (*slab.as_ptr()).in_use == 0 {
+                    *link = (*slab.as_ptr()).next;
+                    alloc::alloc::dealloc(slab.as_ptr().cast(), self.slab_layout());
+                    self.slabs -= 1;
+                    released += self.slab_size;
+                } else {
+                    link = &mut (*slab.as_ptr()).next;
+                }
+            }
+        }
+        released
+    }
+
+    /// Current statistics for this cache
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name,
+            object_size: self.object_size,
+            objects_per_slab: self.objects_per_slab(),
+            slabs: self.slabs,
+            objects_in_use: self.objects_in_use,
+            allocations: self.allocations,
+            memory_bytes: self.slabs * self.slab_size,
+        }
+    }
+
+    fn first_object_offset(&self) -> usize {
+        (mem::size_of::<SlabHeader>() + self.align - 1) & !(self.align - 1)
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (self.slab_size - self.first_object_offset()) / self.object_size
+    }
+
+    /// Layout of the blocks this cache carves into objects
+    pub fn slab_layout(&self) -> Layout {
+        // Slabs are aligned to their size so an object's slab is found by masking
+        unsafe { Layout::from_size_align_unchecked(self.slab_size, self.slab_size) }
+    }
+
+    /// Thread the objects of a new slab onto a free list and offer it
+    ///
+    /// # Safety
+    /// `base` must be an unused block allocated with `slab_layout()`.
+    pub unsafe fn add_slab(&mut self, base: NonNull<u8>) {
+        let first = base.as_ptr() as usize + self.first_object_offset();
+
+        let mut free = None;
+        for index in (0..self.objects_per_slab()).rev() {
+            let object = (first + index * self.object_size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = NonNull::new(object);
+        }
+
+        let slab = base.cast::<SlabHeader>();
+        unsafe {
+            slab.as_ptr().write(SlabHeader {
+                next: self.partial,
+                free,
+                in_use: 0,
+            });
+        }
+        self.partial = Some(slab);
+        self.slabs += 1;
+    }
+}
+
+/// Allocate one object from `cache`, growing it by a slab from the heap if needed
+///
+/// The cache is unlocked while the heap allocates, so memory reclaim started
+/// by a failing allocation can free objects back into it.
+pub fn allocate_from(cache: &Mutex<SlabCache>) -> Option<NonNull<u8>> {
+    let layout = {
+        let mut cache = cache.lock();
+        if let Some(object) = cache.allocate() {
+            return Some(object);
+        }
+        cache.slab_layout()
+    };
+    let slab = NonNull::new(unsafe { alloc::alloc::alloc(layout) })?;
+    let mut cache = cache.lock();
+    unsafe { cache.add_slab(slab) };
+    cache.allocate()
+}
+
+/// Index of the general cache serving `layout`, if it is small enough
+pub fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// Caches registered by subsystems, for statistics and reclaim
+static REGISTERED_CACHES: Mutex<Vec<&'static Mutex<SlabCache>>> = Mutex::new(Vec::new());
+
+/// Statistics for the general caches and all registered object caches
+pub fn cache_stats() -> Vec<CacheStats> {
+    let mut stats: Vec<CacheStats> = crate::ALLOCATOR
+        .size_caches()
+        .iter()
+        .map(|cache| cache.lock().stats())
+        .collect();
+    let registered: Vec<_> = REGISTERED_CACHES.lock().clone();
+    stats.extend(registered.iter().map(|cache| cache.lock().stats()));
+    stats
+}
+
+/// Release free slabs from every cache; returns the number of bytes released
+///
+/// Runs on the allocation failure path, so it never allocates and skips
+/// caches that are locked rather than waiting for them.
+pub fn shrink_caches() -> usize {
+    let general: usize = crate::ALLOCATOR
+        .size_caches()
+        .iter()
+        .filter_map(|cache| cache.try_lock())
+        .map(|mut cache| cache.shrink())
+        .sum();
+    let registered = REGISTERED_CACHES.try_lock().map_or(0, |caches| {
+        caches
+            .iter()
+            .filter_map(|cache| cache.try_lock())
+            .map(|mut cache| cache.shrink())
+            .sum()
+    });
+    general + registered
+}
+
+/// A typed cache for a subsystem's own objects
+pub struct ObjectCache<T> {
+    cache: Mutex<SlabCache>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    /// Create an empty cache; no memory is used until the first allocation
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            cache: Mutex::new(SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>())),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Make this cache visible to `cache_stats` and `shrink_caches`
+    pub fn register(&'static self) {
+        REGISTERED_CACHES.lock().push(&self.cache);
+    }
+
+    /// Move `value` into an object from this cache
+    pub fn alloc(&self, value: T) -> Result<CachedObject<'_, T>, MemoryError> {
+        let ptr = allocate_from(&self.cache).ok_or(MemoryError::OutOfMemory)?.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(CachedObject { ptr, cache: self })
+    }
+
+    /// Current statistics for this cache
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+}
+
+/// An object allocated from an `ObjectCache`, returned to it on drop
+pub struct CachedObject<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for CachedObject<'_, T> {}
+unsafe impl<T: Sync> Sync for CachedObject<'_, T> {}
+
+impl<T> Deref for CachedObject<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CachedObject<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for CachedObject<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.lock().deallocate(self.ptr.cast());
+        }
+    }
+}
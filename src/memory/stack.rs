@@ -0,0 +1,155 @@
+//! Kernel stacks with guard pages for KewveOS
+//!
+//! Kernel stacks are carved out of a virtual range reserved from `vmalloc`
+//! in fixed-size slots. The lowest page of every slot is never mapped, so
+//! each stack has an unmapped guard page directly below it and the next
+//! slot's guard page directly above it. Running off either end faults
+//! instead of silently corrupting a neighbouring stack.
+//!
+//! Other kernel regions, such as the heap, are protected by the guard page
+//! `vmalloc` leaves after every region. The bootloader leaves the page below
+//! the boot stack unmapped as well; `init` finds it so that hits on it can
+//! be reported too.
+
+use super::{paging, vmalloc, with_memory, MemoryError, MemoryFlags, PAGE_SIZE};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, Size4KiB},
+    VirtAddr,
+};
+
+/// Usable size of each kernel stack
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024; // 16 KiB
+/// Maximum number of kernel stacks alive at once
+pub const MAX_KERNEL_STACKS: usize = 1024;
+
+/// Pages searched below the stack pointer for the boot stack's guard page
+const MAX_BOOT_STACK_PAGES: usize = 1024;
+
+/// A stack slot is one guard page followed by the stack itself
+const SLOT_SIZE: u64 = (PAGE_SIZE + KERNEL_STACK_SIZE) as u64;
+
+/// Start of the range holding the stack slots, set by `init`
+static STACKS_START: AtomicU64 = AtomicU64::new(0);
+
+/// Start of the guard page below the boot stack, or zero if none was found
+static BOOT_STACK_GUARD: AtomicU64 = AtomicU64::new(0);
+
+/// Bitmap of slots in use
+static SLOTS: Mutex<[u64; MAX_KERNEL_STACKS / 64]> = Mutex::new([0; MAX_KERNEL_STACKS / 64]);
+
+/// A guard page that was hit by a memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardPage {
+    /// The guard page below the kernel stack in slot `slot`
+    KernelStack { slot: usize },
+    /// The guard page after the kernel region named `name`, such as the heap
+    Region { name: &'static str },
+    /// The guard page below the stack the bootloader set up
+    BootStack,
+}
+
+/// Identify the guard page containing `addr`, if any
+pub fn guard_page(addr: VirtAddr) -> Option<GuardPage> {
+    let stacks_start = STACKS_START.load(Ordering::Relaxed);
+    let stacks_end = stacks_start + MAX_KERNEL_STACKS as u64 * SLOT_SIZE;
+    if stacks_start != 0 && (stacks_start..stacks_end).contains(&addr.as_u64()) {
+        let offset = addr.as_u64() - stacks_start;
+        return (offset % SLOT_SIZE < PAGE_SIZE as u64)
+            .then_some(GuardPage::KernelStack { slot: (offset / SLOT_SIZE) as usize });
+    }
+    let boot_guard = BOOT_STACK_GUARD.load(Ordering::Relaxed);
+    if boot_guard != 0 && (boot_guard..boot_guard + PAGE_SIZE as u64).contains(&addr.as_u64()) {
+        return Some(GuardPage::BootStack);
+    }
+    vmalloc::guard_page_owner(addr).map(|name| GuardPage::Region { name })
+}
+
+/// A mapped kernel stack, unmapped and freed on drop
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Map a fresh kernel stack in a free slot
+    pub fn allocate() -> Result<Self, MemoryError> {
+        let slot = claim_slot().ok_or(MemoryError::OutOfMemory)?;
+        let stack = KernelStack { slot };
+        let first_page = Page::containing_address(stack.bottom());
+        with_memory(|mapper, frame_allocator| {
+            paging::map_allocated_range(
+                mapper,
+                frame_allocator,
+                first_page,
+                KERNEL_STACK_SIZE / PAGE_SIZE,
+                MemoryFlags::KERNEL_DATA,
+            )
+        })
+        .inspect_err(|_| release_slot(slot))?;
+        Ok(stack)
+    }
+
+    /// Slot index of this stack, as reported by `guard_page`
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Lowest usable address of the stack
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START.load(Ordering::Relaxed) + self.slot as u64 * SLOT_SIZE + PAGE_SIZE as u64)
+    }
+
+    /// Initial stack pointer; the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + KERNEL_STACK_SIZE as u64
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let first_page = Page::containing_address(self.bottom());
+        let _ = with_memory(|mapper, frame_allocator| {
+            paging::unmap_and_free_range(mapper, frame_allocator, first_page, KERNEL_STACK_SIZE / PAGE_SIZE)
+        });
+        release_slot(self.slot);
+    }
+}
+
+/// Reserve the virtual range holding all stack slots and find the boot
+/// stack's guard page
+///
+/// Must run on the boot stack.
+pub(super) fn init() -> Result<(), MemoryError> {
+    let size = MAX_KERNEL_STACKS * SLOT_SIZE as usize;
+    let start = vmalloc::reserve("kernel stacks", size, PAGE_SIZE, MemoryFlags::KERNEL_DATA)?;
+    STACKS_START.store(start.as_u64(), Ordering::Relaxed);
+
+    // The first unmapped page below the current stack pointer is the guard
+    let mut page = Page::<Size4KiB>::containing_address(VirtAddr::new(crate::backtrace::frame_pointer() as u64));
+    for _ in 0..MAX_BOOT_STACK_PAGES {
+        page -= 1;
+        if !paging::is_mapped(page.start_address()) {
+            BOOT_STACK_GUARD.store(page.start_address().as_u64(), Ordering::Relaxed);
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn claim_slot() -> Option<usize> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut slots = SLOTS.lock();
+        let (word, bits) = slots.iter_mut().enumerate().find(|(_, bits)| **bits != u64::MAX)?;
+        let bit = bits.trailing_ones() as usize;
+        *bits |= 1 << bit;
+        Some(word * 64 + bit)
+    })
+}
+
+fn release_slot(slot: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SLOTS.lock()[slot / 64] &= !(1 << (slot % 64));
+    })
+}
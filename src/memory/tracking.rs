@@ -0,0 +1,228 @@
+//! Heap allocation tracking for KewveOS
+//!
+//! Compiled in with the `heap-tracking` cargo feature. Every allocation made
+//! through the global allocator is recorded with its size, the subsystem tag
+//! active when it was made and the return addresses of its callers, so that
+//! outstanding allocations can be dumped over serial to hunt leaks.
+//!
+//! Subsystems tag their allocations by holding the guard returned by
+//! `enter` while they allocate. Allocations made outside any tag are
+//! attributed to `UNTAGGED`.
+//!
+//! The tracker never allocates: records live in fixed tables, and
+//! allocations that do not fit are only counted.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Maximum number of outstanding allocations recorded individually
+pub const MAX_RECORDS: usize = 2048;
+/// Maximum number of distinct subsystem tags
+pub const MAX_TAGS: usize = 32;
+/// Number of return addresses recorded per allocation
+pub const CALL_DEPTH: usize = 4;
+/// Tag of allocations made outside any tagged section
+pub const UNTAGGED: &str = "untagged";
+
+/// An outstanding allocation
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    pub addr: usize,
+    pub size: usize,
+    pub tag: &'static str,
+    /// Return addresses, innermost first; unused slots are zero
+    pub callers: [usize; CALL_DEPTH],
+}
+
+/// Heap usage of one subsystem tag
+#[derive(Debug, Clone, Copy)]
+pub struct TagStats {
+    pub tag: &'static str,
+    pub live_bytes: usize,
+    pub live_allocations: usize,
+    /// Highest `live_bytes` ever reached
+    pub peak_bytes: usize,
+    pub total_allocations: u64,
+}
+
+impl TagStats {
+    const fn new(tag: &'static str) -> Self {
+        Self {
+            tag,
+            live_bytes: 0,
+            live_allocations: 0,
+            peak_bytes: 0,
+            total_allocations: 0,
+        }
+    }
+}
+
+struct Tracker {
+    records: [Option<AllocationRecord>; MAX_RECORDS],
+    len: usize,
+    tags: [Option<TagStats>; MAX_TAGS],
+    /// Allocations that could not be recorded because the table was full
+    dropped: u64,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        let mut tags = [None; MAX_TAGS];
+        tags[0] = Some(TagStats::new(UNTAGGED));
+        Self {
+            records: [None; MAX_RECORDS],
+            len: 0,
+            tags,
+            dropped: 0,
+        }
+    }
+
+    fn tag_index(&mut self, tag: &'static str) -> usize {
+        if let Some(index) = self.tags.iter().flatten().position(|stats| stats.tag == tag) {
+            return index;
+        }
+        match self.tags.iter().position(Option::is_none) {
+            Some(index) => {
+                self.tags[index] = Some(TagStats::new(tag));
+                index
+            }
+            // Out of tag slots; account the allocations as untagged
+            None => 0,
+        }
+    }
+
+    fn insert(&mut self, record: AllocationRecord, tag: usize) {
+        if self.len == MAX_RECORDS {
+            self.dropped += 1;
+            return;
+        }
+        self.records[self.len] = Some(record);
+        self.len += 1;
+
+        if let Some(stats) = self.tags[tag].as_mut() {
+            stats.live_bytes += record.size;
+            stats.live_allocations += 1;
+            stats.peak_bytes = stats.peak_bytes.max(stats.live_bytes);
+            stats.total_allocations += 1;
+        }
+    }
+
+    fn remove(&mut self, addr: usize) {
+        let Some(index) = self.records[..self.len].iter().flatten().position(|record| record.addr == addr) else {
+            return;
+        };
+        let record = self.records[index].take().expect("record slots below len are filled");
+        self.len -= 1;
+        self.records.swap(index, self.len);
+
+        if let Some(stats) = self.tags.iter_mut().flatten().find(|stats| stats.tag == record.tag) {
+            stats.live_bytes -= record.size;
+            stats.live_allocations -= 1;
+        }
+    }
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// Index into the tag table of the tag currently in effect
+static CURRENT_TAG: AtomicUsize = AtomicUsize::new(0);
+
+/// Restores the previous tag when dropped
+#[must_use = "the tag only applies while the guard is held"]
+pub struct TagGuard {
+    previous: usize,
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        CURRENT_TAG.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// Attribute allocations to `tag` until the returned guard is dropped
+pub fn enter(tag: &'static str) -> TagGuard {
+    let index = without_interrupts(|| TRACKER.lock().tag_index(tag));
+    TagGuard {
+        previous: CURRENT_TAG.swap(index, Ordering::Relaxed),
+    }
+}
+
+/// Record a successful allocation; called by the global allocator
+#[inline(always)]
+pub(super) fn record_alloc(addr: *mut u8, size: usize) {
+    let callers = callers();
+    without_interrupts(|| {
+        // The tracker never allocates, so it can only be locked here by an
+        // allocation inside a tracker call; skip the record instead of deadlocking
+        let Some(mut tracker) = TRACKER.try_lock() else {
+            return;
+        };
+        let tag = CURRENT_TAG.load(Ordering::Relaxed);
+        let record = AllocationRecord {
+            addr: addr as usize,
+            size,
+            tag: tracker.tags[tag].map_or(UNTAGGED, |stats| stats.tag),
+            callers,
+        };
+        tracker.insert(record, tag);
+    })
+}
+
+/// Forget a freed allocation; called by the global allocator
+pub(super) fn record_dealloc(addr: *mut u8) {
+    without_interrupts(|| TRACKER.lock().remove(addr as usize))
+}
+
+/// Usage of every tag seen so far
+pub fn tag_stats() -> [Option<TagStats>; MAX_TAGS] {
+    without_interrupts(|| TRACKER.lock().tags)
+}
+
+/// Number of allocations that were made but could not be recorded
+pub fn dropped_records() -> u64 {
+    without_interrupts(|| TRACKER.lock().dropped)
+}
+
+/// Print per-tag usage and every outstanding allocation over serial
+pub fn dump() {
+    without_interrupts(|| {
+        let tracker = TRACKER.lock();
+        crate::serial_println!("Heap allocations by tag:");
+        for stats in tracker.tags.iter().flatten() {
+            crate::serial_println!(
+                "  {:<16} {:>8} bytes live in {:>5} allocations, peak {:>8} bytes, {} total allocations",
+                stats.tag,
+                stats.live_bytes,
+                stats.live_allocations,
+                stats.peak_bytes,
+                stats.total_allocations
+            );
+        }
+
+        crate::serial_println!("Outstanding heap allocations: {}", tracker.len);
+        for record in tracker.records[..tracker.len].iter().flatten() {
+            crate::serial_print!("  {:#x} {:>6} bytes [{}] from", record.addr, record.size, record.tag);
+            for caller in record.callers.iter().take_while(|&&caller| caller != 0) {
+                crate::serial_print!(" {:#x}", caller);
+            }
+            crate::serial_println!();
+        }
+        if tracker.dropped > 0 {
+            crate::serial_println!("  ({} allocations were not recorded)", tracker.dropped);
+        }
+    })
+}
+
+/// Return addresses of the innermost `CALL_DEPTH` frames above the caller
+///
+/// Follows the frame pointer chain, which the kernel is built to keep.
+#[inline(always)]
+fn callers() -> [usize; CALL_DEPTH] {
+    let mut callers = [0; CALL_DEPTH];
+    let chain = crate::backtrace::return_addresses(crate::backtrace::frame_pointer());
+    for (caller, return_address) in callers.iter_mut().zip(chain) {
+        *caller = return_address;
+    }
+    callers
+}
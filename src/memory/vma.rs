@@ -0,0 +1,175 @@
+//! Virtual memory areas for KewveOS
+//!
+//! A virtual memory area reserves a range of virtual addresses without
+//! backing it. Frames are allocated and mapped by the page fault handler the
+//! first time a page in the area is touched, so large reservations such as
+//! heaps, stacks and mapped files only cost memory for the pages in use.
+
+use super::shared::SharedMemory;
+use super::{vmalloc, MemoryError, MemoryFlags, PAGE_SIZE};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+/// Source of page contents for file-backed areas
+pub trait PageSource: Send + Sync {
+    /// Fill `page` with the contents found at byte `offset` of the source
+    fn read_page(&self, offset: u64, page: &mut [u8]) -> Result<(), MemoryError>;
+}
+
+/// How pages of an area are populated on first access
+#[derive(Clone)]
+pub enum AreaKind {
+    /// Zero-filled memory such as heaps
+    Anonymous,
+    /// Zero-filled stack that is populated as it grows down
+    Stack,
+    /// Pages read from a backing source starting at `offset`
+    FileBacked { source: Arc<dyn PageSource>, offset: u64 },
+    /// A mapping of a shared memory object; pages are mapped up front, never on fault
+    Shared(Arc<SharedMemory>),
+}
+
+impl core::fmt::Debug for AreaKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            AreaKind::Anonymous => write!(f, "Anonymous"),
+            AreaKind::Stack => write!(f, "Stack"),
+            AreaKind::FileBacked { offset, .. } => write!(f, "FileBacked {{ offset: {:#x} }}", offset),
+            AreaKind::Shared(object) => write!(f, "Shared {{ handle: {} }}", object.handle()),
+        }
+    }
+}
+
+/// A lazily populated range of virtual memory
+#[derive(Debug, Clone)]
+pub struct VirtualMemoryArea {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: MemoryFlags,
+    pub kind: AreaKind,
+}
+
+impl VirtualMemoryArea {
+    /// Create an area covering `pages` pages from `start`
+    pub fn new(name: &'static str, start: VirtAddr, pages: usize, flags: MemoryFlags, kind: AreaKind) -> Self {
+        Self {
+            name,
+            start,
+            end: start + (pages * PAGE_SIZE) as u64,
+            flags,
+            kind,
+        }
+    }
+
+    /// Whether `addr` falls inside this area
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Whether an access described by `error` is permitted by the area's flags
+    pub fn permits(&self, error: PageFaultErrorCode) -> bool {
+        if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !self.flags.writable {
+            return false;
+        }
+        if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !self.flags.executable {
+            return false;
+        }
+        if error.contains(PageFaultErrorCode::USER_MODE) && !self.flags.user_accessible {
+            return false;
+        }
+        true
+    }
+
+    /// Fill a freshly allocated page at `page_addr` with the area's initial contents
+    pub fn populate(&self, page_addr: VirtAddr, page: &mut [u8]) -> Result<(), MemoryError> {
+        match &self.kind {
+            AreaKind::Anonymous | AreaKind::Stack => {
+                page.fill(0);
+                Ok(())
+            }
+            AreaKind::FileBacked { source, offset } => {
+                source.read_page(offset + (page_addr - self.start), page)
+            }
+            // Every page was mapped when the area was created
+            AreaKind::Shared(_) => Err(MemoryError::InvalidVirtualAddress(page_addr)),
+        }
+    }
+}
+
+/// A set of non-overlapping areas keyed by start address
+#[derive(Debug, Default, Clone)]
+pub struct AreaSet {
+    areas: BTreeMap<u64, VirtualMemoryArea>,
+}
+
+impl AreaSet {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Add an area, rejecting misaligned or overlapping ranges and W+X permissions
+    pub fn insert(&mut self, area: VirtualMemoryArea) -> Result<(), MemoryError> {
+        area.flags.validate()?;
+        if !area.start.is_aligned(PAGE_SIZE as u64) || !area.end.is_aligned(PAGE_SIZE as u64) || area.start >= area.end {
+            return Err(MemoryError::InvalidVirtualAddress(area.start));
+        }
+        let overlaps = self
+            .areas
+            .range(..area.end.as_u64())
+            .next_back()
+            .is_some_and(|(_, existing)| existing.end > area.start);
+        if overlaps {
+            return Err(MemoryError::InvalidVirtualAddress(area.start));
+        }
+        self.areas.insert(area.start.as_u64(), area);
+        Ok(())
+    }
+
+    /// Remove the area starting at `start`
+    pub fn remove(&mut self, start: VirtAddr) -> Option<VirtualMemoryArea> {
+        self.areas.remove(&start.as_u64())
+    }
+
+    /// Find the area containing `addr`
+    pub fn find(&self, addr: VirtAddr) -> Option<&VirtualMemoryArea> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(addr))
+    }
+
+    /// Iterate over all areas in address order
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualMemoryArea> {
+        self.areas.values()
+    }
+}
+
+/// Lazily populated areas in the shared kernel address range
+pub static KERNEL_AREAS: Mutex<AreaSet> = Mutex::new(AreaSet::new());
+
+/// Reserve a lazily populated kernel area
+///
+/// An area inside the range managed by `vmalloc` is also reserved there, so
+/// that it cannot overlap a region and no region is placed on top of it later.
+pub fn register_kernel_area(area: VirtualMemoryArea) -> Result<(), MemoryError> {
+    if super::address_space::is_user_address(area.start) {
+        return Err(MemoryError::InvalidVirtualAddress(area.start));
+    }
+    let start = area.start;
+    let in_vmalloc_range = vmalloc::overlaps_range(area.start, area.end);
+    if in_vmalloc_range {
+        let size = area.end.as_u64().saturating_sub(area.start.as_u64()) as usize;
+        vmalloc::reserve_fixed(area.name, area.start, size, area.flags)?;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| KERNEL_AREAS.lock().insert(area)).inspect_err(|_| {
+        if in_vmalloc_range {
+            let _ = vmalloc::release(start);
+        }
+    })
+}
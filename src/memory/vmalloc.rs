@@ -0,0 +1,322 @@
+//! Kernel virtual address space allocator for KewveOS
+//!
+//! Hands out non-overlapping, named regions of the kernel's virtual address
+//! range for the heap, kernel stacks, MMIO mappings and driver buffers, so
+//! that no subsystem needs to hard-code where it lives. Every region is
+//! followed by at least one unmapped guard page.
+//!
+//! Region bookkeeping lives in a fixed-size table rather than on the heap,
+//! because the heap itself is placed by this allocator.
+
+use super::{paging, with_memory, MemoryError, MemoryFlags, PAGE_SIZE};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageSize, PhysFrame, Size2MiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Start of the kernel virtual range managed by this allocator
+pub const KERNEL_VIRT_START: u64 = 0x_4000_0000_0000;
+/// End of the kernel virtual range; user address spaces start here
+pub const KERNEL_VIRT_END: u64 = 0x_6000_0000_0000;
+/// Maximum number of regions alive at once
+pub const MAX_REGIONS: usize = 256;
+
+/// Unmapped gap left after every region
+const GUARD_SIZE: u64 = PAGE_SIZE as u64;
+/// Bytes covered by one level 4 page table entry
+const LEVEL_4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// How a region is backed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Address space only; the owner maps pages itself
+    Reserved,
+    /// Backed by frames allocated for the region, freed on release
+    Allocated,
+    /// Mapped to device memory, which is never freed
+    Mmio { phys: PhysAddr },
+}
+
+/// A named region of kernel virtual address space
+#[derive(Debug, Clone, Copy)]
+pub struct KernelRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: usize,
+    pub flags: MemoryFlags,
+    pub kind: RegionKind,
+}
+
+impl KernelRegion {
+    /// First address past the region
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size as u64
+    }
+
+    fn page_count(&self) -> usize {
+        self.size / PAGE_SIZE
+    }
+}
+
+/// Regions sorted by start address
+struct RegionTable {
+    regions: [Option<KernelRegion>; MAX_REGIONS],
+    len: usize,
+}
+
+impl RegionTable {
+    const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &KernelRegion> {
+        self.regions[..self.len].iter().flatten()
+    }
+
+    /// Find the lowest free range of `size` bytes aligned to `align` and record `region` there
+    fn insert_first_fit(&mut self, mut region: KernelRegion, align: u64) -> Result<VirtAddr, MemoryError> {
+        if self.len == MAX_REGIONS {
+            return Err(MemoryError::OutOfMemory);
+        }
+        let size = region.size as u64;
+        let mut candidate = KERNEL_VIRT_START;
+        let mut index = 0;
+        for existing in self.iter() {
+            candidate = align_up(candidate, align);
+            if candidate + size + GUARD_SIZE <= existing.start.as_u64() {
+                break;
+            }
+            candidate = existing.end().as_u64() + GUARD_SIZE;
+            index += 1;
+        }
+        candidate = align_up(candidate, align);
+        if candidate + size + GUARD_SIZE > KERNEL_VIRT_END {
+            return Err(MemoryError::OutOfMemory);
+        }
+
+        region.start = VirtAddr::new(candidate);
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(region.start)
+    }
+
+    /// Record a region at a fixed address
+    fn insert_fixed(&mut self, region: KernelRegion) -> Result<(), MemoryError> {
+        let overlaps = self
+            .iter()
+            .any(|existing| existing.start < region.end() && region.start < existing.end());
+        if overlaps || self.len == MAX_REGIONS {
+            return Err(MemoryError::InvalidVirtualAddress(region.start));
+        }
+        let index = self.iter().take_while(|existing| existing.start < region.start).count();
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, start: VirtAddr) -> Option<KernelRegion> {
+        let index = self.iter().position(|region| region.start == start)?;
+        let region = self.regions[index].take();
+        self.regions.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.regions[self.len] = None;
+        region
+    }
+}
+
+static REGIONS: Mutex<RegionTable> = Mutex::new(RegionTable::new());
+
+/// Prepare the kernel virtual range
+///
+/// Creates the page tables for the whole range up front so that every
+/// address space shares them, and fences off any part of the range the
+/// bootloader already uses. Must run before the first address space is
+/// created.
+pub(super) fn init() -> Result<(), MemoryError> {
+    let mut start = KERNEL_VIRT_START;
+    while start < KERNEL_VIRT_END {
+        let addr = VirtAddr::new(start);
+        let in_use = with_memory(|mapper, frame_allocator| {
+            let in_use = !mapper.level_4_table()[addr.p4_index()].is_unused();
+            paging::reserve_level_4_entry(mapper, frame_allocator, addr)?;
+            Ok(in_use)
+        })?;
+        if in_use {
+            let region = KernelRegion {
+                name: "bootloader",
+                start: addr,
+                size: LEVEL_4_ENTRY_SIZE as usize,
+                flags: MemoryFlags::KERNEL_DATA,
+                kind: RegionKind::Reserved,
+            };
+            without_interrupts(|| REGIONS.lock().insert_fixed(region))?;
+        }
+        start += LEVEL_4_ENTRY_SIZE;
+    }
+    Ok(())
+}
+
+/// Reserve `size` bytes of address space without mapping anything
+///
+/// The owner is responsible for mapping and unmapping pages inside the region.
+pub fn reserve(name: &'static str, size: usize, align: usize, flags: MemoryFlags) -> Result<VirtAddr, MemoryError> {
+    insert(name, size, align, flags, RegionKind::Reserved)
+}
+
+/// Reserve `size` bytes at the fixed address `start` without mapping anything
+///
+/// Fails if the range is not page aligned, leaves the managed range or
+/// overlaps another region.
+pub(super) fn reserve_fixed(name: &'static str, start: VirtAddr, size: usize, flags: MemoryFlags) -> Result<(), MemoryError> {
+    let end = start.as_u64() + size as u64;
+    if size == 0
+        || !start.is_aligned(PAGE_SIZE as u64)
+        || size as u64 & (PAGE_SIZE as u64 - 1) != 0
+        || start.as_u64() < KERNEL_VIRT_START
+        || end > KERNEL_VIRT_END
+    {
+        return Err(MemoryError::InvalidVirtualAddress(start));
+    }
+    flags.validate()?;
+    let region = KernelRegion {
+        name,
+        start,
+        size,
+        flags,
+        kind: RegionKind::Reserved,
+    };
+    without_interrupts(|| REGIONS.lock().insert_fixed(region))
+}
+
+/// Whether any part of `start..end` lies in the managed range
+pub(super) fn overlaps_range(start: VirtAddr, end: VirtAddr) -> bool {
+    start.as_u64() < KERNEL_VIRT_END && end.as_u64() > KERNEL_VIRT_START
+}
+
+/// Reserve `size` bytes and back them with freshly allocated frames
+pub fn allocate(name: &'static str, size: usize, flags: MemoryFlags) -> Result<VirtAddr, MemoryError> {
+    let start = insert(name, size, PAGE_SIZE, flags, RegionKind::Allocated)?;
+    let pages = align_up(size as u64, PAGE_SIZE as u64) as usize / PAGE_SIZE;
+    with_memory(|mapper, frame_allocator| {
+        paging::map_allocated_range(mapper, frame_allocator, Page::containing_address(start), pages, flags)
+    })
+    .inspect_err(|_| {
+        without_interrupts(|| REGIONS.lock().remove(start));
+    })?;
+    Ok(start)
+}
+
+/// Map `size` bytes of device memory at `phys` uncached
+///
+/// Returns the virtual address corresponding to `phys`, which need not be
+/// page aligned.
+pub fn map_mmio(name: &'static str, phys: PhysAddr, size: usize) -> Result<VirtAddr, MemoryError> {
+    let frame = PhysFrame::containing_address(phys);
+    let offset = phys - frame.start_address();
+    let mapped_size = align_up(offset + size as u64, PAGE_SIZE as u64) as usize;
+    // Match the physical alignment so large apertures can use 2 MiB pages
+    let align = if mapped_size as u64 >= Size2MiB::SIZE && phys.is_aligned(Size2MiB::SIZE) {
+        Size2MiB::SIZE as usize
+    } else {
+        PAGE_SIZE
+    };
+
+    let kind = RegionKind::Mmio { phys: frame.start_address() };
+    let start = insert(name, mapped_size, align, MemoryFlags::DEVICE, kind)?;
+    with_memory(|mapper, frame_allocator| {
+        paging::map_range(
+            mapper,
+            frame_allocator,
+            Page::containing_address(start),
+            frame,
+            mapped_size / PAGE_SIZE,
+            MemoryFlags::DEVICE,
+        )
+    })
+    .inspect_err(|_| {
+        without_interrupts(|| REGIONS.lock().remove(start));
+    })?;
+    Ok(start + offset)
+}
+
+/// Release the region containing `addr`, unmapping it according to its kind
+pub fn release(addr: VirtAddr) -> Result<KernelRegion, MemoryError> {
+    let region = without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let start = regions
+            .iter()
+            .find(|region| region.start <= addr && addr < region.end())
+            .map(|region| region.start)
+            .ok_or(MemoryError::InvalidVirtualAddress(addr))?;
+        regions.remove(start).ok_or(MemoryError::InvalidVirtualAddress(addr))
+    })?;
+
+    let first_page = Page::containing_address(region.start);
+    match region.kind {
+        RegionKind::Reserved => {}
+        RegionKind::Allocated => with_memory(|mapper, frame_allocator| {
+            paging::unmap_and_free_range(mapper, frame_allocator, first_page, region.page_count())
+        })?,
+        RegionKind::Mmio { .. } => with_memory(|mapper, frame_allocator| {
+            paging::unmap_range(mapper, frame_allocator, first_page, region.page_count())
+        })?,
+    }
+    Ok(region)
+}
+
+/// Snapshot of all regions in address order
+pub fn regions() -> Vec<KernelRegion> {
+    without_interrupts(|| REGIONS.lock().iter().copied().collect())
+}
+
+/// Whether the region table is locked
+pub(super) fn is_locked() -> bool {
+    REGIONS.is_locked()
+}
+
+/// Name of the region whose trailing guard page contains `addr`
+///
+/// Returns `None` without waiting if the region table is locked, so it is
+/// safe to call from fault handlers.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let regions = REGIONS.try_lock()?;
+    let owner = regions
+        .iter()
+        .find(|region| region.end() <= addr && addr < region.end() + GUARD_SIZE)
+        .map(|region| region.name);
+    owner
+}
+
+fn insert(
+    name: &'static str,
+    size: usize,
+    align: usize,
+    flags: MemoryFlags,
+    kind: RegionKind,
+) -> Result<VirtAddr, MemoryError> {
+    if size == 0 || !align.is_power_of_two() {
+        return Err(MemoryError::InvalidVirtualAddress(VirtAddr::zero()));
+    }
+    flags.validate()?;
+    let region = KernelRegion {
+        name,
+        start: VirtAddr::zero(),
+        size: align_up(size as u64, PAGE_SIZE as u64) as usize,
+        flags,
+        kind,
+    };
+    let align = (align as u64).max(PAGE_SIZE as u64);
+    without_interrupts(|| REGIONS.lock().insert_first_fit(region, align))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
@@ -1,6 +1,7 @@
 //! x86_64 platform implementation
 
 use super::{Platform, PlatformInfo, PlatformError};
+use crate::acpi;
 
 /// x86_64 platform implementation
 pub struct X86_64Platform {
@@ -33,10 +34,10 @@ impl Platform for X86_64Platform {
         // Initialize x86_64 specific features
         // This would include things like:
         // - Setting up CPU features
-        // - Initializing APIC
         // - Setting up memory management
         
-        // For now, we'll just return Ok
+        // The APIC and drivers read their configuration from the ACPI tables
+        acpi::init().map_err(|_| PlatformError::HardwareError)?;
         Ok(())
     }
     
@@ -51,6 +52,20 @@ impl Platform for X86_64Platform {
     }
 }
 
+/// Number of processors described by the firmware; 1 without ACPI
+pub fn processor_count() -> usize {
+    acpi::tables().map_or(1, |tables| tables.processor_count())
+}
+
+/// Run CPUID for `leaf`
+pub fn cpuid(leaf: u32) -> core::arch::x86_64::CpuidResult {
+    // `__cpuid` is only marked safe on newer toolchains
+    #[allow(unused_unsafe)]
+    unsafe {
+        core::arch::x86_64::__cpuid(leaf)
+    }
+}
+
 /// Get the CPU vendor string
 pub fn get_cpu_vendor() -> [u8; 12] {
     let mut vendor = [0; 12];
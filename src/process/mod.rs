@@ -7,14 +7,18 @@
 //! - Cross-platform scheduling
 //! - Resource management and cleanup
 
+pub mod user;
+
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::println;
-use x86_64::{VirtAddr, PhysAddr};
+use crate::memory::{self, AddressSpace, KernelStack, MemoryError};
 use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
 
 /// Process states
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -28,6 +32,80 @@ pub enum ProcessState {
 /// Process identifier
 pub type ProcessId = u64;
 
+/// Process management errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The process address space could not be set up
+    AddressSpaceCreationFailed(MemoryError),
+    /// No process with the given identifier exists
+    NoSuchProcess(ProcessId),
+    /// The process kernel stack could not be mapped
+    KernelStackAllocationFailed(MemoryError),
+    /// The process has not terminated yet
+    NotTerminated(ProcessId),
+    /// The current process is missing or is the kernel process
+    NoUserProcess,
+}
+
+impl core::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ProcessError::AddressSpaceCreationFailed(err) => write!(f, "Failed to create address space: {}", err),
+            ProcessError::NoSuchProcess(pid) => write!(f, "No such process: {}", pid),
+            ProcessError::KernelStackAllocationFailed(err) => write!(f, "Failed to allocate kernel stack: {}", err),
+            ProcessError::NotTerminated(pid) => write!(f, "Process {} has not terminated", pid),
+            ProcessError::NoUserProcess => write!(f, "No user process is running"),
+        }
+    }
+}
+
+/// A CPU exception raised by a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// Exception vector
+    pub vector: u8,
+    pub instruction_pointer: VirtAddr,
+    pub error_code: Option<u64>,
+    /// Faulting address, for page faults
+    pub address: Option<VirtAddr>,
+}
+
+impl core::fmt::Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{} at {:#x}",
+            crate::interrupts::crash::exception_name(self.vector),
+            self.instruction_pointer.as_u64()
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " accessing {:#x}", address.as_u64())?;
+        }
+        if let Some(code) = self.error_code {
+            write!(f, " (error code {:#x})", code)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a process terminated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Terminated by the kernel to reclaim memory
+    Killed,
+    /// Terminated by a CPU exception
+    Fault(Fault),
+}
+
+impl core::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ExitReason::Killed => write!(f, "killed"),
+            ExitReason::Fault(fault) => write!(f, "fault: {}", fault),
+        }
+    }
+}
+
 /// Process control block
 #[derive(Debug, Clone)]
 pub struct ProcessControlBlock {
@@ -38,6 +116,17 @@ pub struct ProcessControlBlock {
     pub stack_pointer: Option<u64>,
     pub program_counter: Option<u64>,
     pub registers: [u64; 16], // General purpose registers
+    /// Private page tables; `None` runs in the kernel address space
+    pub address_space: Option<Arc<AddressSpace>>,
+    /// Stack used while the process runs in the kernel, guarded against overflow
+    pub kernel_stack: Option<Arc<KernelStack>>,
+    /// Set once the process has terminated
+    pub exit_reason: Option<ExitReason>,
+    /// Processes blocked until this one terminates
+    pub waiters: Vec<ProcessId>,
+    /// While the process runs in user mode, the address of the kernel stack
+    /// pointer `user::run_user` resumes at when the process leaves it
+    pub user_resume: Option<usize>,
 }
 
 impl ProcessControlBlock {
@@ -51,6 +140,11 @@ impl ProcessControlBlock {
             stack_pointer: None,
             program_counter: None,
             registers: [0; 16],
+            address_space: None,
+            kernel_stack: None,
+            exit_reason: None,
+            waiters: Vec::new(),
+            user_resume: None,
         }
     }
     
@@ -63,11 +157,18 @@ impl ProcessControlBlock {
     pub fn state(&self) -> ProcessState {
         self.state
     }
+    
+    /// Load this process's page tables into CR3
+    pub fn activate_address_space(&self) {
+        memory::address_space::switch_to(self.address_space.as_ref());
+    }
 }
 
 /// Process scheduler
 pub struct Scheduler {
     processes: BTreeMap<ProcessId, ProcessControlBlock>,
+    /// Runnable processes; its capacity always covers every process, so
+    /// requeueing a process never allocates
     ready_queue: Vec<ProcessId>,
     current_process: Option<ProcessId>,
 }
@@ -86,6 +187,7 @@ impl Scheduler {
     pub fn add_process(&mut self, pcb: ProcessControlBlock) {
         let pid = pcb.id;
         self.processes.insert(pid, pcb);
+        self.ready_queue.reserve(self.processes.len().saturating_sub(self.ready_queue.len()));
         self.ready_queue.push(pid);
     }
     
@@ -95,6 +197,18 @@ impl Scheduler {
         self.processes.remove(&pid)
     }
     
+    /// Get a process by identifier
+    pub fn process(&self, pid: ProcessId) -> Option<&ProcessControlBlock> {
+        self.processes.get(&pid)
+    }
+    
+    /// Find the process whose kernel stack occupies `slot`
+    pub fn kernel_stack_owner(&self, slot: usize) -> Option<&ProcessControlBlock> {
+        self.processes
+            .values()
+            .find(|process| process.kernel_stack.as_ref().is_some_and(|stack| stack.slot() == slot))
+    }
+    
     /// Get the current process
     pub fn current_process(&self) -> Option<&ProcessControlBlock> {
         self.current_process.and_then(|pid| self.processes.get(&pid))
@@ -125,8 +239,52 @@ impl Scheduler {
             if let Some(process) = self.processes.get_mut(&pid) {
                 process.set_state(ProcessState::Blocked);
             }
+            self.ready_queue.retain(|&x| x != pid);
+            self.current_process = None;
+        }
+    }
+    
+    /// Terminate a process and wake the processes waiting for it
+    ///
+    /// A process with waiters stays in the table with its exit reason until
+    /// one of them reaps it; one without is removed on the next switch.
+    /// Returns `false` if there is no such live process.
+    pub fn terminate(&mut self, pid: ProcessId, reason: ExitReason) -> bool {
+        let waiter_count = match self.processes.get_mut(&pid) {
+            Some(process) if process.state != ProcessState::Terminated => {
+                process.set_state(ProcessState::Terminated);
+                process.exit_reason = Some(reason);
+                process.waiters.len()
+            }
+            _ => return false,
+        };
+        self.ready_queue.retain(|&x| x != pid);
+        if self.current_process == Some(pid) {
             self.current_process = None;
         }
+        // Index rather than copy the list; requeueing the waiters fits in the
+        // ready queue's reserved capacity, so termination never allocates
+        for index in 0..waiter_count {
+            let waiter = self.processes[&pid].waiters[index];
+            self.unblock(waiter);
+        }
+        true
+    }
+    
+    /// Remove terminated processes nobody waits for, and drop the address
+    /// spaces and kernel stacks of the rest
+    ///
+    /// Must not run on the kernel stack or in the address space of a
+    /// terminated process.
+    fn release_terminated(&mut self) {
+        self.processes
+            .retain(|_, process| process.state != ProcessState::Terminated || !process.waiters.is_empty());
+        for process in self.processes.values_mut() {
+            if process.state == ProcessState::Terminated {
+                process.address_space = None;
+                process.kernel_stack = None;
+            }
+        }
     }
     
     /// Unblock a process
@@ -140,6 +298,9 @@ impl Scheduler {
     }
 }
 
+/// Next process identifier to hand out
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
 lazy_static! {
     /// Global scheduler instance
     pub static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
@@ -154,26 +315,158 @@ pub fn init() {
     println!("Process management initialized");
 }
 
-/// Create a new process
-pub fn create_process(name: String) -> ProcessId {
-    static mut NEXT_PID: ProcessId = 1;
+/// Create a new process with its own address space
+pub fn create_process(name: String) -> Result<ProcessId, ProcessError> {
+    let address_space = AddressSpace::new().map_err(ProcessError::AddressSpaceCreationFailed)?;
+    let kernel_stack = KernelStack::allocate().map_err(ProcessError::KernelStackAllocationFailed)?;
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
     
-    let pid = unsafe {
-        let id = NEXT_PID;
-        NEXT_PID += 1;
-        id
+    let mut process = ProcessControlBlock::new(pid, name);
+    process.stack_pointer = Some(kernel_stack.top().as_u64());
+    process.address_space = Some(Arc::new(address_space));
+    process.kernel_stack = Some(Arc::new(kernel_stack));
+    SCHEDULER.lock().add_process(process);
+    
+    Ok(pid)
+}
+
+/// Create a copy of `parent` named `name`
+///
+/// The child starts with the parent's registers and priority. Its stack
+/// pointer starts at the top of a kernel stack of its own, since the parent's
+/// points into the parent's stack. Its memory is shared with the parent
+/// copy-on-write, so forking costs page tables only and pages are copied
+/// when either side first writes to them.
+pub fn fork_process(parent: ProcessId, name: String) -> Result<ProcessId, ProcessError> {
+    let (priority, registers, program_counter, parent_space) = {
+        let scheduler = SCHEDULER.lock();
+        let parent_pcb = scheduler.process(parent).ok_or(ProcessError::NoSuchProcess(parent))?;
+        (
+            parent_pcb.priority,
+            parent_pcb.registers,
+            parent_pcb.program_counter,
+            parent_pcb.address_space.clone(),
+        )
     };
     
-    let process = ProcessControlBlock::new(pid, name);
+    let address_space = match parent_space {
+        Some(space) => space.duplicate(),
+        None => AddressSpace::new(),
+    }
+    .map_err(ProcessError::AddressSpaceCreationFailed)?;
+    let kernel_stack = KernelStack::allocate().map_err(ProcessError::KernelStackAllocationFailed)?;
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    
+    let mut process = ProcessControlBlock::new(pid, name);
+    process.priority = priority;
+    process.registers = registers;
+    process.stack_pointer = Some(kernel_stack.top().as_u64());
+    process.program_counter = program_counter;
+    process.address_space = Some(Arc::new(address_space));
+    process.kernel_stack = Some(Arc::new(kernel_stack));
     SCHEDULER.lock().add_process(process);
     
-    pid
+    Ok(pid)
+}
+
+/// Terminate the lowest-priority process to free its memory
+///
+/// Priorities compare numerically, and among equals the newest process is
+/// chosen. The kernel process and the current process are never chosen.
+/// Used by memory reclaim, which may run while the scheduler is locked; in
+/// that case nothing is terminated. Runs on the allocation failure path, so
+/// it never allocates: the victim's waiters are requeued within the ready
+/// queue's reserved capacity. Returns the identifier of the terminated process.
+pub fn terminate_lowest_priority() -> Option<ProcessId> {
+    let (victim, released) = {
+        let mut scheduler = SCHEDULER.try_lock()?;
+        let current = scheduler.current_process;
+        let victim = scheduler
+            .processes
+            .values()
+            .filter(|process| process.state != ProcessState::Terminated)
+            .filter(|process| process.id != 0 && Some(process.id) != current)
+            .min_by_key(|process| (process.priority, core::cmp::Reverse(process.id)))?
+            .id;
+        scheduler.terminate(victim, ExitReason::Killed);
+        let process = scheduler.processes.get_mut(&victim)?;
+        crate::serial_println!("Out of memory: terminated process {} (PID: {})", process.name, victim);
+        // Waiters reap the process; without any it is removed right away
+        let released = if process.waiters.is_empty() {
+            (None, None, scheduler.remove_process(victim))
+        } else {
+            (process.address_space.take(), process.kernel_stack.take(), None)
+        };
+        (victim, released)
+    };
+    
+    // Dropping these outside the scheduler lock releases the process memory
+    drop(released);
+    Some(victim)
+}
+
+/// Terminate the current process after it raised `fault` in user mode
+///
+/// Only a process running in user mode through `user::run_user` is
+/// terminated, and nothing is done while the scheduler is locked, since the
+/// fault may have interrupted the scheduler itself. `run_user` switches to
+/// the next process, which releases the address space and kernel stack.
+/// Returns the identifier and name of the terminated process and the kernel
+/// stack pointer to pass to `user::abandon`.
+pub fn terminate_current(fault: Fault) -> Option<(ProcessId, String, u64)> {
+    let mut scheduler = SCHEDULER.try_lock()?;
+    let process = scheduler.current_process_mut()?;
+    let resume = process.user_resume.take()?;
+    let (pid, name) = (process.id, process.name.clone());
+    scheduler.terminate(pid, ExitReason::Fault(fault));
+    // `run_user` is still waiting in the frame holding the saved pointer
+    let resume = unsafe { *(resume as *const u64) };
+    Some((pid, name, resume))
+}
+
+/// Wait for `pid` to terminate
+///
+/// Returns the exit reason if it already has. Otherwise the current process
+/// is blocked until it does and `None` is returned.
+pub fn wait(pid: ProcessId) -> Result<Option<ExitReason>, ProcessError> {
+    let mut scheduler = SCHEDULER.lock();
+    let waiter = scheduler.current_process;
+    let process = scheduler.processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess(pid))?;
+    if let Some(reason) = process.exit_reason {
+        return Ok(Some(reason));
+    }
+    if let Some(waiter) = waiter {
+        process.waiters.push(waiter);
+        scheduler.block_current();
+    }
+    Ok(None)
+}
+
+/// Remove a terminated process from the process table
+pub fn reap(pid: ProcessId) -> Result<ExitReason, ProcessError> {
+    let mut scheduler = SCHEDULER.lock();
+    let reason = scheduler
+        .process(pid)
+        .ok_or(ProcessError::NoSuchProcess(pid))?
+        .exit_reason
+        .ok_or(ProcessError::NotTerminated(pid))?;
+    scheduler.remove_process(pid);
+    Ok(reason)
 }
 
 /// Switch to the next process
 pub fn switch_to_next_process() {
+    memory::pressure::check();
+    
     let mut scheduler = SCHEDULER.lock();
-    if let Some(next_process) = scheduler.schedule() {
-        println!("Switching to process: {} (PID: {})", next_process.name, next_process.id);
+    match scheduler.schedule() {
+        Some(next_process) => {
+            println!("Switching to process: {} (PID: {})", next_process.name, next_process.id);
+            next_process.activate_address_space();
+        }
+        // Nothing is runnable, but a terminated process may still be loaded
+        None => memory::address_space::switch_to(None),
     }
+    // Terminated processes can no longer be running or mapped
+    scheduler.release_terminated();
 }
\ No newline at end of file
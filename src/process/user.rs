@@ -0,0 +1,111 @@
+//! Running processes in user mode and abandoning them after a fault
+//!
+//! `run_user` saves the kernel's callee-saved registers and flags on the
+//! current stack, records the stack pointer in the process control block and
+//! drops to ring 3 with `iretq`. When the user code faults, the exception
+//! handler terminates the process and calls `abandon`, which rewrites the
+//! interrupt frame so that returning from the handler lands in
+//! `return_to_kernel`. That restores the saved registers and returns from
+//! `run_user`, which switches to the next process.
+
+use super::{ProcessError, SCHEDULER};
+use crate::interrupts::gdt;
+use core::arch::global_asm;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+global_asm!(
+    ".global enter_user_mode",
+    "enter_user_mode:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [r8], rsp",
+    // Frame for iretq: SS, RSP, RFLAGS with interrupts enabled, CS, RIP
+    "push rcx",
+    "push rsi",
+    "push 0x202",
+    "push rdx",
+    "push rdi",
+    "iretq",
+    "",
+    // Entered through `iretq` with the stack pointer saved above
+    ".global return_to_kernel",
+    "return_to_kernel:",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+extern "C" {
+    fn enter_user_mode(entry: u64, stack: u64, code_segment: u64, data_segment: u64, resume: *mut u64);
+    fn return_to_kernel() -> !;
+}
+
+/// Run the current process in user mode from `entry` on the user stack `stack`
+///
+/// Returns once the process has left user mode, which without system calls
+/// only happens when it faults and is terminated. By then the scheduler has
+/// switched to the next process, so the dead process's address space is no
+/// longer loaded.
+///
+/// # Safety
+///
+/// `entry` and `stack` must be mapped user accessible in the current
+/// process's address space, and no locks may be held, since the user code
+/// runs with interrupts enabled.
+pub unsafe fn run_user(entry: VirtAddr, stack: VirtAddr) -> Result<(), ProcessError> {
+    // The saved stack pointer lives in this frame, which outlives the user code
+    let mut resume = 0u64;
+    let resume_ptr = core::ptr::addr_of_mut!(resume);
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let process = scheduler
+            .current_process_mut()
+            .filter(|process| process.id != 0)
+            .ok_or(ProcessError::NoUserProcess)?;
+        process.user_resume = Some(resume_ptr as usize);
+        process.activate_address_space();
+        Ok(())
+    })?;
+
+    let selectors = gdt::selectors();
+    enter_user_mode(
+        entry.as_u64(),
+        stack.as_u64(),
+        selectors.user_code.0 as u64,
+        selectors.user_data.0 as u64,
+        resume_ptr,
+    );
+    super::switch_to_next_process();
+    Ok(())
+}
+
+/// Rewrite `stack_frame` so that returning from the exception handler
+/// abandons the interrupted user code and returns from `run_user`
+///
+/// `resume` is the stack pointer `run_user` saved, as returned by
+/// `terminate_current`. Interrupts stay disabled until the flags saved on
+/// entry are restored.
+pub(crate) fn abandon(stack_frame: &mut InterruptStackFrame, resume: u64) {
+    let selectors = gdt::selectors();
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(return_to_kernel as unsafe extern "C" fn() -> ! as usize as u64);
+            frame.code_segment = selectors.kernel_code.0 as u64;
+            frame.cpu_flags = 0x2;
+            frame.stack_pointer = VirtAddr::new(resume);
+            frame.stack_segment = selectors.kernel_data.0 as u64;
+        });
+    }
+}